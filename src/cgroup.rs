@@ -0,0 +1,177 @@
+//! A cgroup v2 the child and units are placed in for --cgroup, so that limits such as
+//! `memory.max` apply to everything they run and `stop` can kill whatever is left of them
+//! with `cgroup.kill`, including processes that left their process group
+
+use anyhow::{Context, Result, bail};
+use nix::{
+    libc,
+    sys::signal::{Signal, kill},
+    unistd::Pid,
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    os::fd::RawFd,
+    path::{Component, Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct Cgroup {
+    path: PathBuf,
+}
+
+/// Where the unified hierarchy is mounted, usually /sys/fs/cgroup or /sys/fs/cgroup/unified
+/// on hybrid systems
+fn unified_root() -> Option<PathBuf> {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").ok()?;
+    mountinfo.lines().find_map(|line| {
+        // The mount point is the fifth field, the filesystem type follows the separator
+        let (fields, rest) = line.split_once(" - ")?;
+        if rest.split(' ').next() != Some("cgroup2") {
+            return None;
+        }
+        fields.split(' ').nth(4).map(PathBuf::from)
+    })
+}
+
+impl Cgroup {
+    /// Creates the cgroup at `path` under the unified hierarchy unless it exists, and
+    /// writes the settings into it, enabling their controllers for it first. Returns None
+    /// when cgroup v2 isn't mounted
+    pub fn create(path: &Path, settings: &[(String, String)]) -> Result<Option<Self>> {
+        if path
+            .components()
+            .any(|component| matches!(component, Component::ParentDir))
+        {
+            bail!("the cgroup path may not contain ..: {}", path.display());
+        }
+        let Some(root) = unified_root() else {
+            return Ok(None);
+        };
+        let relative = path.strip_prefix(&root).unwrap_or(path);
+        let relative = relative.strip_prefix("/").unwrap_or(relative);
+        if relative.as_os_str().is_empty() {
+            bail!("the cgroup can't be the root cgroup: {}", path.display());
+        }
+        let cgroup = Cgroup {
+            path: root.join(relative),
+        };
+        std::fs::create_dir_all(&cgroup.path)
+            .with_context(|| format!("failed to create cgroup {}", cgroup.path.display()))?;
+
+        for (key, value) in settings {
+            // A controller is only available to a cgroup once each ancestor enables it
+            // for its children, from the root down, e.g. memory for memory.max
+            let controller = key.split_once('.').map_or(key.as_str(), |(name, _)| name);
+            if controller != "cgroup" {
+                let ancestors: Vec<&Path> = relative.ancestors().skip(1).collect();
+                for ancestor in ancestors.into_iter().rev() {
+                    enable_controller(&root.join(ancestor), controller)?;
+                }
+            }
+            let setting = cgroup.path.join(key);
+            std::fs::write(&setting, value)
+                .with_context(|| format!("failed to set {} to {}", setting.display(), value))?;
+        }
+        Ok(Some(cgroup))
+    }
+
+    /// The cgroup a previous `start` created, to stop what's left in it
+    pub fn open(path: &Path) -> Self {
+        Cgroup {
+            path: path.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// `cgroup.procs` opened for writing, for processes to move themselves into the cgroup
+    /// with `join` between fork and exec, before they can fork anything
+    pub fn procs_file(&self) -> Result<File> {
+        let procs = self.path.join("cgroup.procs");
+        OpenOptions::new()
+            .write(true)
+            .open(&procs)
+            .with_context(|| format!("failed to open {}", procs.display()))
+    }
+
+    /// Moves a process into the cgroup. Only what it forks from then on follows it
+    pub fn add(&self, pid: Pid) -> Result<()> {
+        std::fs::write(self.path.join("cgroup.procs"), pid.to_string())
+            .with_context(|| format!("failed to move {} to cgroup {}", pid, self.path.display()))
+    }
+
+    /// The processes in the cgroup itself, not the ones in cgroups below it
+    pub fn processes(&self) -> Result<Vec<Pid>> {
+        let procs = match std::fs::read_to_string(self.path.join("cgroup.procs")) {
+            Ok(procs) => procs,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| format!("failed to read cgroup {}", self.path.display()));
+            }
+        };
+        Ok(procs
+            .lines()
+            .filter_map(|pid| pid.trim().parse().ok())
+            .map(Pid::from_raw)
+            .collect())
+    }
+
+    /// Kills every process in the cgroup and below it with `cgroup.kill`, or on kernels
+    /// before 5.14 that lack it by sending each process of the cgroup SIGKILL
+    pub fn kill(&self) -> Result<()> {
+        match std::fs::write(self.path.join("cgroup.kill"), "1") {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                for pid in self.processes()? {
+                    let _ = kill(pid, Signal::SIGKILL);
+                }
+                Ok(())
+            }
+            Err(err) => {
+                Err(err).with_context(|| format!("failed to kill cgroup {}", self.path.display()))
+            }
+        }
+    }
+}
+
+/// Moves the calling process into the cgroup of the `procs_file`, only doing what's safe
+/// between fork and exec
+pub fn join(procs_file: RawFd) -> std::io::Result<()> {
+    if unsafe { libc::write(procs_file, b"0".as_ptr().cast(), 1) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn enable_controller(cgroup: &Path, controller: &str) -> Result<()> {
+    // Controllers bound to a cgroup v1 hierarchy, e.g. on hybrid systems, aren't available
+    let controllers = std::fs::read_to_string(cgroup.join("cgroup.controllers"))
+        .with_context(|| format!("failed to read the controllers of {}", cgroup.display()))?;
+    let available = controllers
+        .split_whitespace()
+        .any(|name| name == controller);
+    if !available {
+        bail!(
+            "the {} controller is not available in {}",
+            controller,
+            cgroup.display()
+        );
+    }
+    let subtree_control = cgroup.join("cgroup.subtree_control");
+    let enabled = std::fs::read_to_string(&subtree_control)
+        .with_context(|| format!("failed to read {}", subtree_control.display()))?;
+    if enabled.split_whitespace().any(|name| name == controller) {
+        return Ok(());
+    }
+    std::fs::write(&subtree_control, format!("+{}", controller)).with_context(|| {
+        format!(
+            "failed to enable the {} controller in {}",
+            controller,
+            subtree_control.display()
+        )
+    })
+}
@@ -0,0 +1,101 @@
+//! The control socket the daemon listens on in the status dir.
+//!
+//! The protocol is line-based: a client connects, sends a single command line such as
+//! `status` or `stop SIGINT` and the daemon answers with a single line, `ok` or `err`,
+//! optionally followed by a space and a payload, before closing the connection. After
+//! answering `attach`, the daemon keeps the connection open to stream the child's output.
+
+use anyhow::{Context, Result, bail};
+use std::{
+    io::{BufRead, BufReader, ErrorKind, Write},
+    os::unix::net::{UnixListener, UnixStream},
+    path::Path,
+    time::Duration,
+};
+
+/// How long a client waits for the daemon to answer
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Binds the socket, replacing one left behind by a daemon that didn't shut down cleanly
+pub fn listen(path: &Path) -> Result<UnixListener> {
+    let _ = std::fs::remove_file(path);
+    UnixListener::bind(path)
+        .with_context(|| format!("failed to bind control socket: {}", path.display()))
+}
+
+/// Reads the command line of a connection, split into the command and its argument
+pub fn read_request(stream: &UnixStream) -> Result<(String, String)> {
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .context("failed to read command")?;
+
+    let line = line.trim();
+    let (command, arg) = line.split_once(' ').unwrap_or((line, ""));
+    Ok((command.to_string(), arg.trim().to_string()))
+}
+
+pub fn write_reply(mut stream: &UnixStream, reply: &Result<String>) -> Result<()> {
+    let line = match reply {
+        Ok(payload) if payload.is_empty() => "ok\n".to_string(),
+        Ok(payload) => format!("ok {}\n", payload),
+        Err(err) => format!("err {:#}\n", err),
+    };
+    stream
+        .write_all(line.as_bytes())
+        .context("failed to write reply")
+}
+
+/// Sends a command to the daemon and returns the payload of its reply. `None` means no
+/// daemon is listening, e.g. because it isn't running or predates the control socket
+pub fn request(path: &Path, command: &str) -> Result<Option<String>> {
+    match send(path, command)? {
+        Some(mut reader) => read_reply(&mut reader).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// Asks the daemon to stream the output of the child, which then arrives on the returned
+/// connection until the daemon exits. `None` means no daemon is listening
+pub fn attach(path: &Path) -> Result<Option<BufReader<UnixStream>>> {
+    let Some(mut reader) = send(path, "attach")? else {
+        return Ok(None);
+    };
+    read_reply(&mut reader)?;
+    // The output may be quiet for any amount of time
+    reader.get_ref().set_read_timeout(None)?;
+    Ok(Some(reader))
+}
+
+fn send(path: &Path, command: &str) -> Result<Option<BufReader<UnixStream>>> {
+    let mut stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(err) if matches!(err.kind(), ErrorKind::NotFound | ErrorKind::ConnectionRefused) => {
+            return Ok(None);
+        }
+        Err(err) => {
+            return Err(err).with_context(|| {
+                format!("failed to connect to control socket: {}", path.display())
+            });
+        }
+    };
+    stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+    writeln!(stream, "{}", command).context("failed to send command")?;
+    Ok(Some(BufReader::new(stream)))
+}
+
+fn read_reply(reader: &mut BufReader<UnixStream>) -> Result<String> {
+    let mut line = String::new();
+    reader
+        .read_line(&mut line)
+        .context("failed to read reply")?;
+
+    let line = line.trim_end();
+    let (status, payload) = line.split_once(' ').unwrap_or((line, ""));
+    match status {
+        "ok" => Ok(payload.to_string()),
+        "err" => bail!("{}", payload),
+        _ => bail!("unexpected reply from daemon: {}", line),
+    }
+}
@@ -0,0 +1,191 @@
+//! Active health checks that probe the child while it is running
+
+use crate::{
+    format_duration,
+    http::{self, HttpUrl},
+};
+use anyhow::{Context, Result, bail};
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often and how strictly the child is probed
+#[derive(Debug, Clone, Copy)]
+pub struct HealthOptions {
+    pub interval: Duration,
+    pub failures: u32,
+    pub start_delay: Duration,
+    /// How long a child has to pass its first check before it counts as failed to start
+    pub ready_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone)]
+pub enum HealthCheck {
+    Http(HttpUrl),
+    Tcp(TcpAddr),
+    Exec(ExecProbe),
+    Heartbeat(Heartbeat),
+}
+
+impl HealthCheck {
+    /// Probes the child once, returning why it is considered unhealthy
+    pub fn probe(&self, timeout: Duration) -> Result<()> {
+        match self {
+            HealthCheck::Http(url) => {
+                let code = http::request(url, "GET", None, timeout)?;
+                if !(200..300).contains(&code) {
+                    bail!("unexpected status code {}", code);
+                }
+                Ok(())
+            }
+            HealthCheck::Tcp(addr) => http::connect(&addr.host, addr.port, timeout).map(|_| ()),
+            HealthCheck::Exec(probe) => probe.run(timeout),
+            HealthCheck::Heartbeat(heartbeat) => heartbeat.check(),
+        }
+    }
+
+    /// Probes the child once like `probe`, describing the outcome as JSON for `guarderd
+    /// health`
+    pub fn report(&self, timeout: Duration) -> serde_json::Value {
+        let started = Instant::now();
+        let result = self.probe(timeout);
+        serde_json::json!({
+            "healthy": result.is_ok(),
+            "check": self.to_string(),
+            "duration_ms": started.elapsed().as_millis() as u64,
+            "error": result.err().map(|err| format!("{:#}", err)),
+        })
+    }
+}
+
+impl std::fmt::Display for HealthCheck {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthCheck::Http(url) => write!(f, "GET {}", url),
+            HealthCheck::Tcp(addr) => write!(f, "connect {}", addr),
+            HealthCheck::Exec(probe) => write!(f, "`{}`", probe.command),
+            HealthCheck::Heartbeat(heartbeat) => {
+                write!(f, "heartbeat {}", heartbeat.path.display())
+            }
+        }
+    }
+}
+
+/// A `host:port` pair to connect to
+#[derive(Debug, Clone)]
+pub struct TcpAddr {
+    host: String,
+    port: u16,
+}
+
+impl TcpAddr {
+    pub fn parse(addr: &str) -> Result<Self, String> {
+        let (host, port) = addr
+            .rsplit_once(':')
+            .ok_or_else(|| format!("expected host:port: {}", addr))?;
+        let port = port
+            .parse()
+            .map_err(|_| format!("invalid port: {}", addr))?;
+
+        if host.is_empty() {
+            return Err(format!("missing host: {}", addr));
+        }
+
+        Ok(TcpAddr {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl std::fmt::Display for TcpAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.host, self.port)
+    }
+}
+
+/// A shell command run in the same environment and working directory as the child,
+/// exiting with zero when the child is healthy
+#[derive(Debug, Clone)]
+pub struct ExecProbe {
+    pub command: String,
+    pub env_clear: bool,
+    pub env: Vec<(String, String)>,
+    pub workdir: PathBuf,
+}
+
+impl ExecProbe {
+    fn run(&self, timeout: Duration) -> Result<()> {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(&self.command)
+            .current_dir(&self.workdir)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null());
+        if self.env_clear {
+            command.env_clear();
+        }
+        command.envs(self.env.iter().map(|(key, value)| (key, value)));
+
+        let mut child = command.spawn().context("failed to spawn probe")?;
+
+        // A probe outliving its interval is killed so that probes don't pile up
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = child.try_wait().context("failed to wait for probe")? {
+                if !status.success() {
+                    bail!("probe exited with {}", status);
+                }
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("probe timed out after {}", format_duration(timeout));
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+    }
+}
+
+/// A file the child touches to show it is alive, for children that don't expose a port
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    pub path: PathBuf,
+    /// How long ago the file may have been touched last
+    pub timeout: Duration,
+    /// When the checked child was spawned, a file last touched before was left by an
+    /// earlier one
+    pub since: Option<SystemTime>,
+}
+
+impl Heartbeat {
+    fn check(&self) -> Result<()> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("failed to read heartbeat file {}", self.path.display()))?;
+        if self.since.is_some_and(|since| modified < since) {
+            bail!(
+                "heartbeat file {} wasn't touched since the child was spawned",
+                self.path.display()
+            );
+        }
+        // A clock that went back counts as a fresh heartbeat
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        if age > self.timeout {
+            bail!(
+                "heartbeat file {} was last touched {:.1}s ago, more than {}",
+                self.path.display(),
+                age.as_secs_f64(),
+                format_duration(self.timeout)
+            );
+        }
+        Ok(())
+    }
+}
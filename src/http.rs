@@ -0,0 +1,125 @@
+//! A minimal HTTP/1.0 client over plain TCP for health checks and webhooks
+
+use anyhow::{Context, Result, bail};
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+/// A plain `http://host[:port][/path]` URL, TLS is not supported
+#[derive(Debug, Clone)]
+pub struct HttpUrl {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl HttpUrl {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let rest = url
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("only http:// URLs are supported: {}", url))?;
+
+        let (authority, path) = match rest.find('/') {
+            Some(pos) => rest.split_at(pos),
+            None => (rest, "/"),
+        };
+
+        let (host, port) = match authority.rsplit_once(':') {
+            // Leave the colons of a bare IPv6 address alone
+            Some((host, port)) if !port.contains(']') => {
+                let port = port
+                    .parse()
+                    .map_err(|_| format!("invalid port in URL: {}", url))?;
+                (host, port)
+            }
+            _ => (authority, 80),
+        };
+
+        if host.is_empty() {
+            return Err(format!("missing host in URL: {}", url));
+        }
+
+        Ok(HttpUrl {
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+}
+
+impl std::fmt::Display for HttpUrl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "http://{}:{}{}", self.host, self.port, self.path)
+    }
+}
+
+pub fn connect(host: &str, port: u16, timeout: Duration) -> Result<TcpStream> {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs = (host, port)
+        .to_socket_addrs()
+        .with_context(|| format!("failed to resolve {}:{}", host, port))?;
+
+    let mut last_err = None;
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = Some(err),
+        }
+    }
+
+    match last_err {
+        Some(err) => Err(err).with_context(|| format!("failed to connect to {}:{}", host, port)),
+        None => bail!("no addresses found for {}:{}", host, port),
+    }
+}
+
+/// Sends a request with an optional JSON body and returns the response status code
+pub fn request(
+    url: &HttpUrl,
+    method: &str,
+    json_body: Option<&str>,
+    timeout: Duration,
+) -> Result<u16> {
+    let mut stream = connect(&url.host, url.port, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    let mut request = format!(
+        "{} {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\nUser-Agent: guarderd\r\n",
+        method, url.path, url.host
+    );
+    if let Some(body) = json_body {
+        request.push_str(&format!(
+            "Content-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        ));
+    } else {
+        request.push_str("\r\n");
+    }
+    stream
+        .write_all(request.as_bytes())
+        .context("failed to send request")?;
+
+    // Only the status line matters, e.g. "HTTP/1.1 200 OK"
+    let mut buf = [0; 64];
+    let mut len = 0;
+    while len < buf.len() && !buf[..len].contains(&b'\n') {
+        let n = stream
+            .read(&mut buf[len..])
+            .context("failed to read response")?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+
+    let status_line = String::from_utf8_lossy(&buf[..len]);
+    status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| format!("malformed response: {}", status_line.trim()))
+}
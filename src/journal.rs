@@ -0,0 +1,78 @@
+//! The event journal, `events.jsonl`, one JSON object per line for each lifecycle event
+//! of the guard. It is meant for machines rather than people, and is rotated by its own
+//! size limit instead of along with the logs
+
+use crate::{compress_log, open_log_file, rotate_log};
+use anyhow::{Context, Result};
+use chrono::Utc;
+use serde_json::Value;
+use std::{
+    fs::File,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    keep: u32,
+}
+
+impl Journal {
+    /// Opens the journal for appending, the records of previous runs are kept
+    pub fn open(path: &Path, max_size: u64, keep: u32) -> Result<Self> {
+        let file = open_log_file(path)?;
+        let size = file
+            .metadata()
+            .with_context(|| format!("failed to read {}", path.display()))?
+            .len();
+        Ok(Journal {
+            path: path.to_path_buf(),
+            file,
+            size,
+            max_size,
+            keep,
+        })
+    }
+
+    /// Appends a record of the event with the current time and the given fields, rotating
+    /// the journal first when the record would take it past its maximum size
+    pub fn record(&mut self, event: &str, fields: Value) -> Result<()> {
+        let mut record = serde_json::json!({
+            "timestamp": Utc::now().to_rfc3339(),
+            "event": event,
+        });
+        if let (Value::Object(record), Value::Object(fields)) = (&mut record, fields) {
+            record.extend(fields);
+        }
+        let line = format!("{}\n", record);
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(line.as_bytes())
+            .with_context(|| format!("failed to write to {}", self.path.display()))?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Compresses the journal into `events.jsonl.1.gz`, shifting up the older generations,
+    /// or drops its records when none are kept
+    fn rotate(&mut self) -> Result<()> {
+        if self.keep == 0 {
+            self.file
+                .set_len(0)
+                .with_context(|| format!("failed to truncate {}", self.path.display()))?;
+        } else {
+            let (staged, compressed) = rotate_log(&self.path, self.keep)?;
+            self.file = open_log_file(&self.path)?;
+            compress_log(&staged, &compressed)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
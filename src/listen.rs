@@ -0,0 +1,152 @@
+//! Listening sockets the daemon holds on to and the child inherits, handed over the way
+//! systemd socket activation does: as fds 3 and up, announced with `LISTEN_FDS` and
+//! `LISTEN_PID`. The sockets stay open across restarts, so connections queue up in the
+//! backlog while the child is down instead of being refused
+
+use anyhow::{Context, Result, bail};
+use nix::{libc, unistd};
+use std::{
+    fs::File,
+    net::TcpListener,
+    os::fd::{AsRawFd, FromRawFd, IntoRawFd, RawFd},
+};
+
+/// The fd of the first socket, the ones after it follow in order
+const FIRST_FD: RawFd = 3;
+
+/// Where a socket comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenSource {
+    /// A TCP socket bound to `host:port` by the daemon
+    Tcp(String),
+    /// The sockets systemd passed to guarderd itself with `LISTEN_FDS`
+    Systemd,
+}
+
+pub fn parse(s: &str) -> Result<ListenSource, String> {
+    if s == "systemd" {
+        return Ok(ListenSource::Systemd);
+    }
+    if !s
+        .rsplit_once(':')
+        .is_some_and(|(host, port)| !host.is_empty() && port.parse::<u16>().is_ok())
+    {
+        return Err(format!("expected host:port or systemd: {}", s));
+    }
+    Ok(ListenSource::Tcp(s.to_string()))
+}
+
+/// Opens the sockets at fds 3 and up in the order given, returning how many there are.
+/// It has to run before the daemon opens anything else but the files moved with
+/// `make_way`, as it takes these fds over
+pub fn open(sources: &[ListenSource]) -> Result<usize> {
+    let mut next = FIRST_FD;
+    for source in sources {
+        match source {
+            ListenSource::Systemd => {
+                if next != FIRST_FD {
+                    bail!("the systemd sockets have to come before the other --listen-fd");
+                }
+                // systemd already placed them at 3 and up
+                let count = systemd_fds()?;
+                for fd in FIRST_FD..FIRST_FD + count {
+                    set_cloexec(fd, true).context("failed to take over systemd socket")?;
+                }
+                next += count;
+                // Consumed here, the child is told about its own sockets
+                unsafe {
+                    std::env::remove_var("LISTEN_FDS");
+                    std::env::remove_var("LISTEN_PID");
+                    std::env::remove_var("LISTEN_FDNAMES");
+                }
+            }
+            ListenSource::Tcp(addr) => {
+                let listener = TcpListener::bind(addr)
+                    .with_context(|| format!("failed to listen on {}", addr))?;
+                let fd = listener.into_raw_fd();
+                if fd != next {
+                    // dup3 would silently close whatever is open there
+                    if unsafe { libc::fcntl(next, libc::F_GETFD) } >= 0 {
+                        unsafe { libc::close(fd) };
+                        bail!(
+                            "fd {} is already open, the sockets need fds {} and up",
+                            next,
+                            FIRST_FD
+                        );
+                    }
+                    if unsafe { libc::dup3(fd, next, libc::O_CLOEXEC) } < 0 {
+                        return Err(std::io::Error::last_os_error())
+                            .with_context(|| format!("failed to move socket to fd {}", next));
+                    }
+                    unsafe { libc::close(fd) };
+                }
+                next += 1;
+            }
+        }
+    }
+    Ok((next - FIRST_FD) as usize)
+}
+
+/// Moves a file opened before the sockets, such as the lock file, above the fds `open`
+/// takes over for `sources`
+pub fn make_way(file: File, sources: &[ListenSource]) -> Result<File> {
+    let mut count = 0;
+    for source in sources {
+        count += match source {
+            ListenSource::Systemd => systemd_fds()?,
+            ListenSource::Tcp(_) => 1,
+        };
+    }
+    if file.as_raw_fd() >= FIRST_FD + count {
+        return Ok(file);
+    }
+    // The duplicate shares the open file and its locks, the original is closed on return
+    let fd = unsafe { libc::fcntl(file.as_raw_fd(), libc::F_DUPFD_CLOEXEC, FIRST_FD + count) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to move fd {} out of the way", file.as_raw_fd()));
+    }
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// The number of sockets systemd passed, if they were meant for this process
+fn systemd_fds() -> Result<RawFd> {
+    let pid = std::env::var("LISTEN_PID").context("LISTEN_PID is not set")?;
+    if pid.parse() != Ok(unistd::getpid().as_raw()) {
+        bail!("LISTEN_PID {} is not the PID of guarderd", pid);
+    }
+    let count = std::env::var("LISTEN_FDS").context("LISTEN_FDS is not set")?;
+    count
+        .parse()
+        .ok()
+        .filter(|&count| count > 0)
+        .with_context(|| format!("invalid LISTEN_FDS: {}", count))
+}
+
+fn set_cloexec(fd: RawFd, cloexec: bool) -> std::io::Result<()> {
+    let flags = if cloexec { libc::FD_CLOEXEC } else { 0 };
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, flags) } < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Lets the process about to exec inherit the sockets, to be called between fork and exec
+pub fn inherit(count: usize) -> std::io::Result<()> {
+    for fd in FIRST_FD..FIRST_FD + count as RawFd {
+        set_cloexec(fd, false)?;
+    }
+    Ok(())
+}
+
+/// The command line that runs `argv` with `LISTEN_PID` set to its PID, which is only known
+/// once it runs. A shell exports its own PID and replaces itself with the command
+pub fn command_line(argv: &[String]) -> Vec<String> {
+    let mut command_line = vec![
+        "sh".to_string(),
+        "-c".to_string(),
+        "LISTEN_PID=$$; export LISTEN_PID; exec \"$0\" \"$@\"".to_string(),
+    ];
+    command_line.extend_from_slice(argv);
+    command_line
+}
@@ -1,43 +1,364 @@
+mod cgroup;
+mod control;
+mod health;
+mod http;
+mod journal;
+mod listen;
+mod metrics;
+mod spawner;
+mod syslog;
+mod watch;
+
 use anyhow::{Context, Result, bail};
 use chrono::Utc;
 
-use clap::{Args, Parser, Subcommand};
+use clap::{
+    ArgMatches, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum,
+    parser::ValueSource,
+};
+use flate2::{Compression, write::GzEncoder};
 use fs2::FileExt;
+use health::{ExecProbe, HealthCheck, HealthOptions, Heartbeat, TcpAddr};
+use http::HttpUrl;
 use nix::{
     libc::{self, mmap64, prctl},
-    sys::signal::kill,
-    unistd::{self, ForkResult, Pid, dup2_stderr, dup2_stdout},
+    pty::{OpenptyResult, Winsize, openpty},
+    sys::resource::{Resource, getrlimit, setrlimit},
+    sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, kill, killpg, sigaction},
+    sys::termios::{OutputFlags, SetArg, tcgetattr, tcsetattr},
+    unistd::{self, ForkResult, Gid, Group, Pid, Uid, User, dup2_stderr, dup2_stdout},
 };
+use serde::Deserialize;
+use spawner::{ChildProcess, Clock, OsSpawner, ProcessSpawner, ResourceUsage, SystemClock};
 use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
     fs::{File, OpenOptions},
-    io::{Read, Write},
-    os::unix::process::CommandExt,
-    path::PathBuf,
-    process::{exit, Command},
+    io::{PipeReader, PipeWriter, Read, Seek, SeekFrom, Write},
+    os::fd::OwnedFd,
+    os::linux::net::SocketAddrExt,
+    os::unix::{
+        ffi::OsStrExt,
+        io::{AsFd, AsRawFd},
+        fs::{MetadataExt, PermissionsExt},
+        net::{SocketAddr, UnixDatagram, UnixStream},
+        process::{CommandExt, ExitStatusExt},
+    },
+    path::{Path, PathBuf},
+    process::{Command, ExitCode, ExitStatus, Stdio},
     sync::{
-        atomic::{AtomicBool, AtomicU64}, Arc, Mutex
+        atomic::{AtomicBool, AtomicU8, AtomicU64}, Arc, Mutex, OnceLock
     },
     thread,
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 const STATUS_PATH: &str = "guarderd.status.d";
+const DEFAULT_RESTART_INTERVAL: Duration = Duration::from_secs(5);
 const DEFAULT_MAX_LOG_SIZE_MIB: u64 = 10;
+const DEFAULT_LOG_KEEP: u32 = 5;
+const DEFAULT_LOG_BUFFER_KIB: usize = 64;
+/// The largest --log-buffer-kib, 1 GiB. The kernel doesn't make pipes larger than 2 GiB,
+/// and each log thread allocates a read buffer of the same size
+const MAX_LOG_BUFFER_KIB: usize = 1 << 20;
+const DEFAULT_JOURNAL_MAX_SIZE_MIB: u64 = 10;
+const DEFAULT_JOURNAL_KEEP: u32 = 5;
+const DEFAULT_HEALTH_FAILURES: u32 = 3;
+/// How often watched paths are scanned for changes
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(250);
+/// How often --lock-wait retries taking the lock, and how often it reports that it waits
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const LOCK_PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+/// How often --exit-with-parent checks whether the parent is still there
+const PARENT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the log thread waits for output before checking for shutdown, and how long
+/// the pipe has to stay quiet on shutdown for the remaining output to count as drained
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long writing output to an `attach` client may block before it is dropped
+const ATTACH_WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+/// How long shutdown waits for the log threads to write the output left in the pipes
+const LOG_DRAIN_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many of the latest log rotations `state.json` keeps
+const LOG_ROTATION_HISTORY: usize = 10;
+/// How often --log-overflow drop reports the output it dropped
+const LOG_DROP_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+/// How long the probe of `guarderd health` may take, below the reply timeout of the
+/// control socket
+const HEALTH_QUERY_TIMEOUT: Duration = Duration::from_secs(4);
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+// A child that stays up at least this long resets the backoff to the base interval
+const BACKOFF_RESET_UPTIME: Duration = Duration::from_secs(60);
+// The CPU seconds a child may use past --limit-cpu, after SIGXCPU, before it is killed
+const CPU_LIMIT_GRACE_SECS: u64 = 5;
+
+
+/// Failures the CLI exits with a dedicated code for, so scripts can tell them apart from
+/// other errors, which exit with 1
+#[derive(Debug)]
+enum Failure {
+    /// Another guard holds the lock of the status dir
+    LockHeld(String),
+    /// The command to supervise doesn't resolve to an executable
+    CommandNotFound(String),
+    /// No daemon is running for the status dir
+    DaemonNotRunning(String),
+    /// `wait` gave up before the child exited
+    Timeout(String),
+}
+
+impl Failure {
+    fn exit_code(&self) -> u8 {
+        match self {
+            Failure::LockHeld(_) => 2,
+            Failure::CommandNotFound(_) => 3,
+            Failure::DaemonNotRunning(_) => 4,
+            Failure::Timeout(_) => 5,
+        }
+    }
+}
+
+impl std::fmt::Display for Failure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Failure::LockHeld(message)
+            | Failure::CommandNotFound(message)
+            | Failure::DaemonNotRunning(message)
+            | Failure::Timeout(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for Failure {}
+
+/// Set by the SIGHUP handler, the reload thread picks it up
+static RELOAD_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Counts the SIGUSR1 received, each log thread reopens its log when it changed
+static LOG_REOPEN_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Set once the daemon runs in the background, or with --foreground --tee
+static DAEMON_LOG: OnceLock<DaemonLog> = OnceLock::new();
+
+thread_local! {
+    /// The messages logged while this thread writes to `guarderd.log`, e.g. about its
+    /// rotation, written after the current one rather than waiting for its own lock
+    static NESTED_DAEMON_LOG_LINES: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Where the messages of the daemon go
+enum DaemonLog {
+    /// The terminal with --foreground --tee
+    Terminal(File),
+    File(Mutex<DaemonLogFile>),
+}
+
+/// `guarderd.log`, rotated at --max-log-size-mib and reopened on SIGUSR1 like the logs of
+/// the child
+struct DaemonLogFile {
+    log: LogWriter,
+    max_size: Arc<AtomicU64>,
+    reopen_requests: u64,
+}
+
+impl DaemonLogFile {
+    fn write(&mut self, line: &str) {
+        let requests = LOG_REOPEN_REQUESTS.load(std::sync::atomic::Ordering::SeqCst);
+        if requests != self.reopen_requests {
+            self.reopen_requests = requests;
+            self.log.reopen();
+        }
+        if self.log.size > self.max_size.load(std::sync::atomic::Ordering::Relaxed) {
+            self.log.rotate("Log size exceeded");
+        }
+        self.log.write(line.as_bytes());
+    }
+}
+
+/// `events.jsonl`, opened once the guard holds the lock of the status dir
+static JOURNAL: OnceLock<Mutex<journal::Journal>> = OnceLock::new();
+
+/// The most verbose level of messages printed, see `--log-level`
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+/// How important a message of guarderd is, from the most to the least
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum LogLevel {
+    /// Failures of guarderd itself and giving up on the child
+    Error,
+    /// Crashes and restarts of the child and failed health checks
+    Warn,
+    /// Lifecycle events such as reloads, shutdowns and clean exits
+    Info,
+    /// Details such as each spawn and every poll while stopping
+    Debug,
+}
+
+fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Writes a timestamped message of the daemon to `guarderd.log`, apart from the output of
+/// the child, or to stdout in foreground mode. Messages below `--log-level` are dropped
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        write_daemon_log($level, format_args!($($arg)*))
+    };
+}
+
+fn write_daemon_log(level: LogLevel, message: std::fmt::Arguments) {
+    if !log_enabled(level) {
+        return;
+    }
+    let line = format!("[{}] {}\n", Utc::now().to_rfc3339(), message);
+    match DAEMON_LOG.get() {
+        // A single write per line, so that the lines of different threads don't mix
+        Some(DaemonLog::Terminal(terminal)) => {
+            let mut terminal = terminal;
+            let _ = terminal.write_all(line.as_bytes());
+        }
+        Some(DaemonLog::File(log)) => {
+            let line = NESTED_DAEMON_LOG_LINES.with_borrow_mut(|nested| match nested {
+                Some(lines) => {
+                    lines.push(line);
+                    None
+                }
+                None => {
+                    *nested = Some(Vec::new());
+                    Some(line)
+                }
+            });
+            let Some(line) = line else {
+                return;
+            };
+            let mut log = log.lock().unwrap();
+            log.write(&line);
+            loop {
+                let lines = NESTED_DAEMON_LOG_LINES
+                    .with_borrow_mut(|nested| nested.as_mut().map(std::mem::take))
+                    .unwrap_or_default();
+                if lines.is_empty() {
+                    break;
+                }
+                for line in lines {
+                    log.write(&line);
+                }
+            }
+            NESTED_DAEMON_LOG_LINES.set(None);
+        }
+        None => print!("{}", line),
+    }
+}
+
+/// Appends a lifecycle event to the journal, doing nothing before it is opened
+fn record_event(event: &str, fields: serde_json::Value) {
+    if let Some(journal) = JOURNAL.get()
+        && let Err(err) = journal.lock().unwrap().record(event, fields)
+    {
+        log!(LogLevel::Error, "Failed to write event journal: {:#}", err);
+    }
+}
+
+extern "C" fn request_reload(_: libc::c_int) {
+    RELOAD_REQUESTED.store(true, std::sync::atomic::Ordering::SeqCst);
+}
+
+extern "C" fn request_log_reopen(_: libc::c_int) {
+    LOG_REOPEN_REQUESTS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Turns SIGQUIT into the SIGTERM ctrlc handles, so that it shuts down gracefully too
+extern "C" fn forward_to_sigterm(_: libc::c_int) {
+    unsafe { libc::kill(libc::getpid(), libc::SIGTERM) };
+}
+
+/// Sends a state such as `READY=1` to systemd over $NOTIFY_SOCKET, doing nothing when
+/// not started by systemd
+fn sd_notify(state: &str) -> Result<()> {
+    let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+
+    let datagram = UnixDatagram::unbound().context("failed to create notify socket")?;
+    // A leading @ names a socket in the abstract namespace
+    match socket.as_bytes().strip_prefix(b"@") {
+        Some(name) => {
+            let addr = SocketAddr::from_abstract_name(name)
+                .context("invalid abstract NOTIFY_SOCKET")?;
+            datagram.send_to_addr(state.as_bytes(), &addr)
+        }
+        None => datagram.send_to(state.as_bytes(), &socket),
+    }
+    .with_context(|| format!("failed to send {} to NOTIFY_SOCKET", state))?;
+    Ok(())
+}
+
+/// POSTs an event about the child to the webhook from a separate thread, so a slow
+/// endpoint can't stall supervision. Delivery failures are only logged
+fn send_webhook(
+    url: &HttpUrl,
+    event: &str,
+    pid: Option<Pid>,
+    exit_code: Option<i32>,
+    restart_count: u64,
+) {
+    let url = url.clone();
+    let payload = serde_json::json!({
+        "event": event,
+        "pid": pid.map(Pid::as_raw),
+        "exit_code": exit_code,
+        "timestamp": Utc::now().to_rfc3339(),
+        "restart_count": restart_count,
+    })
+    .to_string();
+
+    thread::spawn(move || {
+        let result = http::request(&url, "POST", Some(&payload), WEBHOOK_TIMEOUT).and_then(
+            |code| match code {
+                200..300 => Ok(()),
+                code => bail!("unexpected status code {}", code),
+            },
+        );
+        if let Err(err) = result {
+            log!(LogLevel::Error, "Failed to deliver webhook to {}: {:#}", url, err);
+        }
+    });
+}
 
+/// Detaches into a daemon with a double fork, the original process runs `parent_cb` and
+/// exits. With `trace` each step and the PIDs it results in are printed to stderr
+fn daemonize(trace: bool, parent_cb: impl FnOnce()) -> Result<Pid> {
+    let trace = |step: String| {
+        if trace {
+            eprintln!("daemonize: {}", step);
+        }
+    };
 
-fn daemonize(parent_cb: impl FnOnce()) -> Result<Pid> {
-    if let ForkResult::Parent { .. } = unsafe { unistd::fork()? } {
+    trace(format!("forking process {}", unistd::getpid()));
+    if let ForkResult::Parent { child } = unsafe { unistd::fork() }.context("first fork failed")? {
+        trace(format!("first fork created process {}", child));
         parent_cb();
         std::process::exit(0);
     }
 
-    unistd::setsid()?;
+    let session = unistd::setsid().context("setsid failed")?;
+    trace(format!(
+        "process {} leads new session {}",
+        unistd::getpid(),
+        session
+    ));
 
-    if let ForkResult::Parent { .. } = unsafe { unistd::fork()? } {
+    if let ForkResult::Parent { child } = unsafe { unistd::fork() }.context("second fork failed")? {
+        trace(format!("second fork created daemon {}", child));
         std::process::exit(0);
     }
 
-    Ok(unistd::getpid())
+    let pid = unistd::getpid();
+    trace(format!(
+        "daemon {} running, parent {}",
+        pid,
+        unistd::getppid()
+    ));
+    Ok(pid)
 }
 
 fn is_process_exist(pid: impl Into<Pid>) -> bool {
@@ -49,436 +370,6686 @@ fn is_process_exist(pid: impl Into<Pid>) -> bool {
     }
 }
 
-fn make_shared_counter() -> &'static AtomicU64 {
-    let addr = unsafe {
-        mmap64(
-            std::ptr::null_mut(),
-            std::mem::size_of::<AtomicU64>(),
-            libc::PROT_READ | libc::PROT_WRITE,
-            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
-            -1,
-            0,
-        )
-    };
-
-    if addr == libc::MAP_FAILED {
-        panic!("mmap64 failed");
-    }
-
-    let counter = unsafe { &mut *(addr as *mut AtomicU64) };
-    counter.store(0, std::sync::atomic::Ordering::SeqCst);
-    
-    counter
+/// A PID from the PID file along with the start time of the process it was recorded for,
+/// so that an unrelated process that reused the PID isn't mistaken for it
+#[derive(Debug, Clone, Copy)]
+struct TrackedPid {
+    pid: Pid,
+    // In clock ticks since boot, missing in PID files written by older versions
+    start_ticks: Option<u64>,
 }
 
-#[derive(Debug)]
-struct Daemon {
-    pid_file: PathBuf,
-    child_pid: Arc<Mutex<Option<Pid>>>,
-    log_path: PathBuf,
-    log_file: Arc<Mutex<Option<File>>>,
-    lock_file: PathBuf,
-    lock_handle: Option<File>,
-    running: Arc<AtomicBool>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProcessState {
+    Running,
+    Exited,
+    /// A process with the PID exists, but it can't be confirmed to be the recorded one
+    Unconfirmed,
 }
 
-impl Daemon {
-    fn new() -> Result<Self> {
-        let current_dir = std::env::current_dir().context("fail to current dir")?;
-        let status_dir = current_dir.join(STATUS_PATH);
-        std::fs::create_dir_all(&status_dir).context("fail to create status dir")?;
-
-        let pid_file = status_dir.join("pid");
-        let lock_file = status_dir.join("lock");
-        let log_path = status_dir.join("stdout.log");
-
-        Ok(Daemon {
-            pid_file,
-            child_pid: Arc::new(None.into()),
-            log_path,
-            lock_file,
-            log_file: Arc::new(Mutex::new(None)),
-            lock_handle: None,
-            running: Arc::new(AtomicBool::new(false)),
-        })
+impl TrackedPid {
+    fn state(&self) -> ProcessState {
+        if !is_process_exist(self.pid) {
+            return ProcessState::Exited;
+        }
+        match self.start_ticks {
+            Some(start_ticks) if process_start_ticks(self.pid) != Some(start_ticks) => {
+                ProcessState::Unconfirmed
+            }
+            _ => ProcessState::Running,
+        }
     }
 
-    fn try_lock(&mut self) -> Result<()> {
-        let file = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(&self.lock_file)
-            .with_context(|| format!("failed to open lock file: {}", self.lock_file.display()))?;
-
-        file.try_lock_exclusive()
-            .context("failed to acquire lock, the daemon may already be running")?;
-
-        self.lock_handle = Some(file);
+    fn is_running(&self) -> bool {
+        self.state() == ProcessState::Running
+    }
 
+    /// Sends a signal, refusing to when the PID may belong to another process by now
+    fn kill(&self, signal: Signal) -> Result<()> {
+        self.ensure_confirmed(signal)?;
+        kill(self.pid, signal)?;
         Ok(())
     }
 
-    fn save_pids(&self, daemon_pid: Pid, child_pid: Pid) -> Result<()> {
-        let content = format!(
-            "daemon_pid: {}\nchild_pid: {}\n",
-            daemon_pid.as_raw(),
-            child_pid.as_raw()
-        );
-        std::fs::write(&self.pid_file, content).context("failed to write PID file")?;
+    /// Like `kill`, but signals the whole process group when the process leads one
+    fn kill_group(&self, signal: Signal) -> Result<()> {
+        self.ensure_confirmed(signal)?;
+        kill_group(self.pid, signal)?;
         Ok(())
     }
 
-    fn get_pids(&self) -> Result<(Pid, Pid)> {
-        if !self.pid_file.exists() {
-            bail!("PID file does not exist: {}", self.pid_file.display());
+    fn ensure_confirmed(&self, signal: Signal) -> Result<()> {
+        if self.state() == ProcessState::Unconfirmed {
+            bail!(
+                "PID {} may have been reused by another process, refusing to send it {}",
+                self.pid,
+                signal
+            );
         }
+        Ok(())
+    }
+}
 
-        let content = std::fs::read_to_string(&self.pid_file).context("failed to read PID file")?;
+/// Sends a signal for the `signal` control command to one unit, or to the child and every
+/// running unit when `unit` is empty, returning the PIDs it was sent to
+fn signal_processes(
+    signal: Signal,
+    unit: &str,
+    child_pid: &Mutex<Option<Pid>>,
+    unit_pids: &Mutex<BTreeMap<String, Pid>>,
+) -> Result<serde_json::Value> {
+    let unit_pids = unit_pids.lock().unwrap();
+    let mut signaled_units = serde_json::Map::new();
+    if !unit.is_empty() {
+        let pid = *unit_pids
+            .get(unit)
+            .with_context(|| format!("unit {} is not running", unit))?;
+        kill(pid, signal)
+            .with_context(|| format!("failed to send {} to unit {} {}", signal, unit, pid))?;
+        signaled_units.insert(unit.to_string(), pid.as_raw().into());
+        return Ok(serde_json::json!({ "units": signaled_units }));
+    }
 
-        let mut daemon_pid: Option<i32> = None;
-        let mut child_pid: Option<i32> = None;
+    let pid = (*child_pid.lock().unwrap()).context("the child process is not running")?;
+    kill(pid, signal).with_context(|| format!("failed to send {} to child {}", signal, pid))?;
+    for (name, pid) in unit_pids.iter() {
+        if kill(*pid, signal).is_ok() {
+            signaled_units.insert(name.clone(), pid.as_raw().into());
+        }
+    }
+    Ok(serde_json::json!({ "child": pid.as_raw(), "units": signaled_units }))
+}
 
-        for line in content.lines() {
-            let line = line.trim();
-            if line.is_empty() {
-                continue;
-            }
+/// Signals the process group a child leads, so that the processes it forked are stopped
+/// along with it. Children that don't lead a group, such as ones spawned by older versions
+/// or sharing the terminal, are signaled alone
+fn kill_group(pid: Pid, signal: Signal) -> nix::Result<()> {
+    if unistd::getpgid(Some(pid)) == Ok(pid) {
+        killpg(pid, signal)
+    } else {
+        kill(pid, signal)
+    }
+}
 
-            if let Some((key, value)) = line.split_once(':') {
-                let key = key.trim();
-                let value = value.trim();
+impl std::fmt::Display for TrackedPid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.pid)
+    }
+}
 
-                match key {
-                    "daemon_pid" => {
-                        daemon_pid =
-                            Some(value.parse::<i32>().with_context(|| {
-                                format!("failed to parse daemon_pid: {}", value)
-                            })?);
-                    }
-                    "child_pid" => {
-                        child_pid =
-                            Some(value.parse::<i32>().with_context(|| {
-                                format!("failed to parse child_pid: {}", value)
-                            })?);
-                    }
-                    _ => {
-                        // Ignore unknown keys for forward compatibility
-                    }
+/// Waits up to `timeout` for a process to exit, killing it with SIGKILL if it doesn't
+fn wait_or_kill(description: &str, pid: TrackedPid, timeout: Duration) -> Result<()> {
+    let start = std::time::Instant::now();
+    loop {
+        match pid.state() {
+            ProcessState::Exited => {
+                if log_enabled(LogLevel::Info) {
+                    println!("{} {} exited", description, pid);
                 }
+                return Ok(());
+            }
+            ProcessState::Unconfirmed => {
+                if log_enabled(LogLevel::Warn) {
+                    println!(
+                        "Warning: PID {} of {} now belongs to another process, not killing it",
+                        pid,
+                        description.to_lowercase()
+                    );
+                }
+                return Ok(());
+            }
+            ProcessState::Running if start.elapsed() >= timeout => {
+                if log_enabled(LogLevel::Warn) {
+                    println!(
+                        "{} {} is still running after {}, killing it",
+                        description,
+                        pid,
+                        format_duration(timeout)
+                    );
+                }
+                return pid
+                    .kill_group(nix::sys::signal::Signal::SIGKILL)
+                    .with_context(|| format!("failed to send SIGKILL to {} {}", description, pid));
+            }
+            ProcessState::Running => {
+                if log_enabled(LogLevel::Debug) {
+                    println!("{} {} is still running", description, pid);
+                }
+                std::thread::sleep(std::time::Duration::from_millis(100));
             }
         }
+    }
+}
 
-        let daemon_pid =
-            daemon_pid.ok_or_else(|| anyhow::anyhow!("daemon_pid not found in PID file"))?;
-        let child_pid =
-            child_pid.ok_or_else(|| anyhow::anyhow!("child_pid not found in PID file"))?;
+/// Parses a signal name such as `SIGINT`, the `SIG` prefix and case are optional
+fn parse_signal(name: &str) -> Result<Signal, String> {
+    let name = name.to_ascii_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    name.parse::<Signal>()
+        .map_err(|_| format!("unknown signal name: {}", name))
+}
+
+/// Parses an octal file mode creation mask such as `027` or `0o077`
+fn parse_umask(s: &str) -> Result<libc::mode_t, String> {
+    parse_mode(s).map_err(|_| format!("invalid umask, expected octal digits: {}", s))
+}
 
-        Ok((Pid::from_raw(daemon_pid), Pid::from_raw(child_pid)))
+/// Parses permission bits in octal, e.g. `660` or `0o660`
+fn parse_mode(s: &str) -> Result<libc::mode_t, String> {
+    let digits = s.strip_prefix("0o").unwrap_or(s);
+    match libc::mode_t::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o777 => Ok(mode),
+        _ => Err(format!("invalid mode, expected octal digits: {}", s)),
     }
+}
 
-    fn stop(&self) -> Result<()> {
-        let (daemon_pid, child_pid) = self.get_pids()?;
-        if !is_process_exist(daemon_pid) {
-            println!("Daemon {} is not running", daemon_pid);
-            return Ok(());
+/// An I/O scheduling class and priority for --ionice, as `ionice(1)` takes them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum IoPriority {
+    Realtime(u8),
+    BestEffort(u8),
+    Idle,
+}
+
+impl IoPriority {
+    /// Parses `CLASS[:LEVEL]`, e.g. `idle`, `best-effort:7` or `realtime:0`, the level
+    /// going from 0 (highest) to 7 and defaulting to 4
+    fn parse(s: &str) -> Result<Self, String> {
+        let (class, level) = match s.split_once(':') {
+            Some((class, level)) => (class, Some(level)),
+            None => (s, None),
+        };
+        let level = match level.map(str::parse::<u8>) {
+            None => None,
+            Some(Ok(level)) if level <= 7 => Some(level),
+            Some(_) => return Err(format!("invalid I/O level, expected 0 to 7: {}", s)),
+        };
+        match class {
+            "realtime" => Ok(IoPriority::Realtime(level.unwrap_or(4))),
+            "best-effort" => Ok(IoPriority::BestEffort(level.unwrap_or(4))),
+            "idle" if level.is_none() => Ok(IoPriority::Idle),
+            "idle" => Err(format!("the idle I/O class takes no level: {}", s)),
+            _ => Err(format!(
+                "invalid I/O class, expected realtime, best-effort or idle: {}",
+                s
+            )),
         }
+    }
+
+    /// The value `ioprio_set` takes, the class in the upper bits and the level below
+    fn value(self) -> libc::c_int {
+        const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+        let (class, level) = match self {
+            IoPriority::Realtime(level) => (1, level),
+            IoPriority::BestEffort(level) => (2, level),
+            IoPriority::Idle => (3, 0),
+        };
+        (class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(level)
+    }
+}
 
-        kill(daemon_pid, nix::sys::signal::Signal::SIGTERM)
-            .with_context(|| format!("failed to send SIGTERM to daemon {}", daemon_pid))?;
+/// Parses a duration such as `90`, `500ms`, `30s`, `15m`, `12h` or `1d`, plain numbers are
+/// seconds
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => s.split_at(pos),
+        None => (s, "s"),
+    };
+    let number: u64 = number
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let unit_millis = match unit {
+        "ms" => 1,
+        "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        "d" => 24 * 60 * 60 * 1000,
+        _ => return Err(format!("invalid duration unit, expected ms, s, m, h or d: {}", s)),
+    };
+    let millis = number
+        .checked_mul(unit_millis)
+        .ok_or_else(|| format!("duration too large: {}", s))?;
+    Ok(Duration::from_millis(millis))
+}
 
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+/// What the supervision loop of the child is handed by `start`
+struct ChildRun<'a> {
+    daemon_pid: Pid,
+    run_info: &'a RunInfo,
+    listen_fds: usize,
+    max_backoff: Option<Duration>,
+    // A child exiting before it failed to start
+    grace_deadline: Instant,
+    // Counts the exits for the parent waiting out the grace period
+    cnt: &'a AtomicU64,
+    // The child started by --overlap-restart, until the supervision loop adopts it
+    takeover: &'a Mutex<Option<Box<dyn ChildProcess>>>,
+    // Whether to tell systemd the child is ready once it is spawned
+    notify_ready: bool,
+}
 
-        if is_process_exist(daemon_pid) {
-            println!(
-                "Daemon {} is still running after SIGTERM, sending SIGKILL",
-                daemon_pid
-            );
-            kill(daemon_pid, nix::sys::signal::Signal::SIGKILL)
-                .with_context(|| format!("failed to send SIGKILL to daemon {}", daemon_pid))?;
+/// Settings of the child process derived from `StartArgs` that have to be looked up
+#[derive(Debug)]
+struct ChildConfig {
+    // The variables of --env-passthrough, taken from the environment of guarderd
+    passed_env: Vec<(String, String)>,
+    file_env: Vec<(String, String)>,
+    workdir: PathBuf,
+    stdin_file: Option<PathBuf>,
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    // The soft and hard limit of each resource
+    rlimits: Vec<(Resource, u64, u64)>,
+    nice: Option<i32>,
+    ioprio: Option<libc::c_int>,
+    // The `cgroup.procs` of --cgroup, set once the cgroup is created
+    cgroup_procs: Option<File>,
+    // The terminal side of --pty, set once it is allocated
+    pty: Option<OwnedFd>,
+}
+
+impl ChildConfig {
+    fn resolve(args: &StartArgs, launch_dir: &Path) -> Result<Self> {
+        let file_env = match &args.env_file {
+            Some(path) => load_env_file(path)?,
+            None => Vec::new(),
+        };
+        // Everything is inherited anyway without --env-clear, variables that aren't set are
+        // left out
+        let passed_env = if args.env_clear {
+            args.env_passthrough
+                .iter()
+                .filter_map(|key| Some((key.clone(), std::env::var(key).ok()?)))
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        // Relative paths in the child command keep resolving against where guarderd was
+        // launched, wherever the daemon itself ends up
+        let workdir = launch_dir.join(args.workdir.as_deref().unwrap_or(Path::new(".")));
+        if !workdir.is_dir() {
+            bail!("working directory does not exist: {}", workdir.display());
         }
 
-        // wait for child exit, with 5 seconds timeout
-        // if the child process is still running after 5 seconds, kill it with SIGKILL
-        println!(
-            "Stopped daemon {}, waiting for child {} to exit",
-            daemon_pid, child_pid
-        );
-        let start = std::time::Instant::now();
-        while start.elapsed().as_secs() < 5 {
-            if !is_process_exist(child_pid) {
-                println!("Child process {} exited", child_pid);
-                break;
-            }
-            println!("Child process {} is still running", child_pid);
-            std::thread::sleep(std::time::Duration::from_millis(100));
+        let stdin_file = args.stdin_file.as_ref().map(|path| launch_dir.join(path));
+        if let Some(path) = &stdin_file {
+            File::open(path)
+                .with_context(|| format!("failed to open stdin file: {}", path.display()))?;
         }
 
-        if is_process_exist(child_pid) {
-            println!(
-                "Child process {} is still running after 5 seconds, killing it",
-                child_pid
-            );
-            kill(child_pid, nix::sys::signal::Signal::SIGKILL)
-                .with_context(|| format!("failed to send SIGKILL to child {}", child_pid))?;
+        let mut uid = None;
+        let mut gid = None;
+        if let Some(user) = &args.user {
+            let (user_uid, primary_gid) = resolve_user(user)?;
+            uid = Some(user_uid);
+            gid = primary_gid;
+        }
+        if let Some(group) = &args.group {
+            gid = Some(resolve_group(group)?);
         }
 
-        Ok(())
-    }
+        let switches_user = uid.is_some_and(|uid| uid != unistd::getuid());
+        let switches_group = gid.is_some_and(|gid| gid != unistd::getgid());
+        if (switches_user || switches_group) && !unistd::geteuid().is_root() {
+            bail!("guarderd must run as root to start the child as a different user or group");
+        }
 
-    fn wait_for_child_grace_period(&self, cnt: &AtomicU64, grace_deadline: Instant) {
-        while Instant::now() < grace_deadline {
-            if cnt.load(std::sync::atomic::Ordering::SeqCst) > 0 {
-                println!(
-                    "[{}] Child process exited in grace period, startup failed",
-                    Utc::now().to_rfc3339()
+        let mut rlimits = Vec::new();
+        for (resource, limit, flag) in [
+            (Resource::RLIMIT_AS, args.limit_as, "--limit-as"),
+            (Resource::RLIMIT_NOFILE, args.limit_nofile, "--limit-nofile"),
+            (Resource::RLIMIT_CPU, args.limit_cpu, "--limit-cpu"),
+        ] {
+            let Some(limit) = limit else {
+                continue;
+            };
+            if limit == 0 {
+                bail!("{} must be greater than zero", flag);
+            }
+            let (_, hard_limit) = getrlimit(resource)
+                .with_context(|| format!("failed to get the current limit for {}", flag))?;
+            let root = unistd::geteuid().is_root();
+            if limit > hard_limit && !root {
+                bail!(
+                    "{} {} is above the hard limit {} of guarderd, raising it requires root",
+                    flag,
+                    limit,
+                    hard_limit
                 );
-                std::process::exit(1);
             }
-            std::thread::sleep(std::time::Duration::from_millis(100));
+            // SIGXCPU at the soft limit, SIGKILL only at the hard one
+            let hard = if resource == Resource::RLIMIT_CPU {
+                let hard = limit.saturating_add(CPU_LIMIT_GRACE_SECS);
+                if root { hard } else { hard.min(hard_limit) }
+            } else {
+                limit
+            };
+            rlimits.push((resource, limit, hard));
         }
-        println!(
-            "[{}] Start successful, exiting grace period",
-            Utc::now().to_rfc3339()
-        );
-    }
 
-    fn start(&mut self, command: Vec<String>, restart_interval: Duration, max_log_size: u64, grace_period: Duration) {
-        if let Err(err) = self.try_lock() {
-            println!(
-                "Failed to acquire lock: {}, may be another instance is running",
-                err
-            );
-            return;
+        // Checked here, as a failure between fork and exec would only tell that the spawn
+        // failed. The priority is set after switching users, which drops root
+        let privileged = unistd::geteuid().is_root() && uid.is_none_or(|uid| uid.is_root());
+        if let Some(nice) = args.nice {
+            if !(-20..=19).contains(&nice) {
+                bail!("--nice must be between -20 and 19: {}", nice);
+            }
+            // RLIMIT_NICE lets unprivileged processes go down to 20 minus its value
+            let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+            let (nice_limit, _) = getrlimit(Resource::RLIMIT_NICE)
+                .context("failed to get the current limit for --nice")?;
+            let floor = 20 - nice_limit.min(40) as i32;
+            if nice < current && nice < floor && !privileged {
+                bail!(
+                    "--nice {} is below the nice value {} of guarderd, lowering it requires \
+                     root, and not switching to another --user",
+                    nice,
+                    current
+                );
+            }
+        }
+        if let Some(IoPriority::Realtime(_)) = args.ionice
+            && !privileged
+        {
+            bail!("--ionice realtime requires root, and not switching to another --user");
         }
 
-        let cnt = make_shared_counter();
-        let grace_deadline = Instant::now() + grace_period;
+        Ok(ChildConfig {
+            passed_env,
+            file_env,
+            workdir,
+            stdin_file,
+            uid,
+            gid,
+            rlimits,
+            nice: args.nice,
+            ioprio: args.ionice.map(IoPriority::value),
+            cgroup_procs: None,
+            pty: None,
+        })
+    }
 
-        let daemon_pid = daemonize(|| self.wait_for_child_grace_period(cnt, grace_deadline)).expect("Failed to daemonize");
+    /// The stdin of the child, a --stdin-file is reopened for every spawn so each run reads
+    /// it from the start
+    fn stdin(&self, args: &StartArgs) -> std::io::Result<Stdio> {
+        let Some(path) = &self.stdin_file else {
+            return Ok(match args.stdin {
+                StdinSource::Null => Stdio::null(),
+                StdinSource::Inherit => Stdio::inherit(),
+            });
+        };
+        File::open(path).map(Stdio::from).map_err(|err| {
+            std::io::Error::new(
+                err.kind(),
+                format!("failed to open stdin file {}: {}", path.display(), err),
+            )
+        })
+    }
 
-        self.running
-            .store(true, std::sync::atomic::Ordering::SeqCst);
+    /// The variables set for the child, later ones taking precedence: --env-passthrough,
+    /// --env-file, then --env
+    fn env<'a>(
+        &'a self,
+        args: &'a StartArgs,
+    ) -> impl DoubleEndedIterator<Item = &'a (String, String)> {
+        self.passed_env
+            .iter()
+            .chain(&self.file_env)
+            .chain(&args.env)
+    }
 
-        let (read_pipe, write_pipe) = std::io::pipe().expect("Failed to create pipe");
+    /// The PATH the child sees, the last one of env() over the daemon's own
+    fn path(&self, args: &StartArgs) -> Option<String> {
+        self.env(args)
+            .rev()
+            .find(|(key, _)| key == "PATH")
+            .map(|(_, value)| value.clone())
+            .or_else(|| (!args.env_clear).then(|| std::env::var("PATH").ok()).flatten())
+    }
+}
 
-        self.setup_signal_handler();
-        self.spawn_log_thread(read_pipe, max_log_size);
-        dup2_stdout(&write_pipe).expect("Failed to redirect stdout");
-        dup2_stderr(&write_pipe).expect("Failed to redirect stderr");
+/// Resolves a user name or numeric uid, along with the user's primary group if known
+fn resolve_user(user: &str) -> Result<(Uid, Option<Gid>)> {
+    if let Ok(uid) = user.parse::<u32>() {
+        let uid = Uid::from_raw(uid);
+        let gid = User::from_uid(uid).ok().flatten().map(|user| user.gid);
+        return Ok((uid, gid));
+    }
 
-        while self.running.load(std::sync::atomic::Ordering::SeqCst) {
-            let mut child = unsafe {
-                Command::new(command[0].clone())
-                    .args(&command[1..])
-                    .stdout(std::process::Stdio::inherit())
-                    .stderr(std::process::Stdio::inherit())
-                    .pre_exec(|| {
-                        prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
-                        std::io::Result::Ok(())
-                    })
-                    .spawn()
-            }.unwrap_or_else(|e| {
-                eprintln!("Failed to spawn child process: {}", e);
-                cnt.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-                std::process::exit(1);
-            });
+    let user = User::from_name(user)
+        .with_context(|| format!("failed to look up user: {}", user))?
+        .with_context(|| format!("no such user: {}", user))?;
+    Ok((user.uid, Some(user.gid)))
+}
 
-            let child_pid = Pid::from_raw(child.id() as i32);
-            self.child_pid.lock().unwrap().replace(child_pid);
-            self.save_pids(daemon_pid, child_pid)
-                .expect("Failed to save PIDs");
+/// Resolves a group name or numeric gid
+fn resolve_group(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse::<u32>() {
+        return Ok(Gid::from_raw(gid));
+    }
 
-            let status = child.wait().expect("Failed to wait for child process");
-            cnt.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+    let group = Group::from_name(group)
+        .with_context(|| format!("failed to look up group: {}", group))?
+        .with_context(|| format!("no such group: {}", group))?;
+    Ok(group.gid)
+}
 
-            if Instant::now() < grace_deadline {
-                println!(
-                    "[{}] Child process exited in grace period, startup failed",
-                    Utc::now().to_rfc3339()
-                );
-                std::process::exit(1);
-            }
+/// Finds the executable a command would run the way exec does, searching `path` for names
+/// without a slash and resolving the others against the working directory
+fn resolve_program(program: &str, workdir: &Path, path: Option<&str>) -> Result<PathBuf> {
+    let is_executable = |candidate: &Path| {
+        candidate
+            .metadata()
+            .is_ok_and(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+    };
 
-            println!(
-                "[{}] Child process {} exited with status {}",
-                Utc::now().to_rfc3339(),
-                child_pid,
-                status
-            );
+    if program.contains('/') {
+        let candidate = workdir.join(program);
+        if !candidate.exists() {
+            bail!("command not found: {}", candidate.display());
+        }
+        if !is_executable(&candidate) {
+            bail!("{} is not an executable file", candidate.display());
+        }
+        return Ok(candidate);
+    }
 
-            if self.running.load(std::sync::atomic::Ordering::SeqCst) {
-                println!(
-                    "[{}] Restarting child process in {} seconds...",
-                    Utc::now().to_rfc3339(),
-                    restart_interval.as_secs()
-                );
+    let candidates: Vec<PathBuf> = path
+        .unwrap_or_default()
+        .split(':')
+        .map(|dir| workdir.join(dir).join(program))
+        .filter(|candidate| candidate.is_file())
+        .collect();
+    if let Some(candidate) = candidates.iter().find(|candidate| is_executable(candidate)) {
+        return Ok(candidate.clone());
+    }
+    // Exec fails the same way, but with a bare "permission denied"
+    match candidates.first() {
+        Some(candidate) => bail!(
+            "{} is not executable, add the execute permission with chmod +x",
+            candidate.display()
+        ),
+        None => bail!("command not found in PATH: {}", program),
+    }
+}
 
-                for _ in 0..restart_interval.as_secs() {
-                    if !self.running.load(std::sync::atomic::Ordering::SeqCst) {
-                        break;
+/// Runs a `--pre-start` or `--post-stop` hook through the shell as the child would run,
+/// with its environment, working directory, user and limits, and waits for it
+fn run_hook(name: &str, hook: &str, args: &StartArgs, child_config: &ChildConfig) -> Result<()> {
+    let argv = ["sh".to_string(), "-c".to_string(), hook.to_string()];
+    let status = build_command(&argv, args, child_config)
+        .status()
+        .with_context(|| format!("failed to run {} hook", name))?;
+    if !status.success() {
+        bail!("{} {}", name, describe_exit(status));
+    }
+    Ok(())
+}
+
+/// Runs the `--stage` commands in order through the shell as the child would run, stopping
+/// at the first one that doesn't exit with 0
+fn run_stages(stages: &[String], args: &StartArgs, child_config: &ChildConfig) -> Result<()> {
+    for (index, stage) in stages.iter().enumerate() {
+        let number = index + 1;
+        let argv = ["sh".to_string(), "-c".to_string(), stage.to_string()];
+        let started_at = Instant::now();
+        let status = build_command(&argv, args, child_config)
+            .status()
+            .with_context(|| format!("failed to run stage {} ({})", number, stage))?;
+        record_event(
+            "stage",
+            serde_json::json!({
+                "stage": number,
+                "command": stage,
+                "exit_code": status.code(),
+                "status": describe_exit(status),
+            }),
+        );
+        if !status.success() {
+            bail!("stage {} ({}) {}", number, stage, describe_exit(status));
+        }
+        log!(
+            LogLevel::Info,
+            "Stage {}/{} ({}) succeeded after {:.1} seconds",
+            number,
+            stages.len(),
+            stage,
+            started_at.elapsed().as_secs_f64()
+        );
+    }
+    Ok(())
+}
+
+/// Builds the command for the child process or a unit, each restart gets a fresh one
+fn build_command(argv: &[String], args: &StartArgs, child_config: &ChildConfig) -> Command {
+    let mut command = Command::new(&argv[0]);
+    command
+        .args(&argv[1..])
+        .current_dir(&child_config.workdir)
+        .stdout(std::process::Stdio::inherit())
+        .stderr(std::process::Stdio::inherit());
+
+    if args.env_clear {
+        command.env_clear();
+    }
+    command.envs(child_config.env(args).map(|(key, value)| (key, value)));
+
+    // The child leads its own process group so that stopping it stops what it forked too.
+    // Only not when it reads the terminal, which a background group would be stopped for.
+    // With --pty it leads a session instead, which comes with a group of its own, so that
+    // it can take the pseudo-terminal as its controlling terminal
+    if args.pty {
+        unsafe {
+            command.pre_exec(|| {
+                unistd::setsid()?;
+                Ok(())
+            });
+        }
+    } else if !(args.foreground && args.stdin == StdinSource::Inherit) {
+        command.process_group(0);
+    }
+
+    // The identity, limits, umask, priorities and cgroup are set between fork and exec, so
+    // they apply to the child only. The daemon keeps owning the status dir and logs
+    if !child_config.rlimits.is_empty()
+        || child_config.uid.is_some()
+        || child_config.gid.is_some()
+        || args.umask.is_some()
+        || child_config.nice.is_some()
+        || child_config.ioprio.is_some()
+        || child_config.cgroup_procs.is_some()
+    {
+        let rlimits = child_config.rlimits.clone();
+        let (uid, gid) = (child_config.uid, child_config.gid);
+        let umask = args.umask;
+        let (nice, ioprio) = (child_config.nice, child_config.ioprio);
+        let cgroup_procs = child_config.cgroup_procs.as_ref().map(File::as_raw_fd);
+        unsafe {
+            command.pre_exec(move || {
+                // Before it can fork anything. Without the permission, the daemon moves it
+                // right after the spawn instead
+                if let Some(procs_file) = cgroup_procs {
+                    let _ = cgroup::join(procs_file);
+                }
+                for &(resource, soft, hard) in &rlimits {
+                    setrlimit(resource, soft, hard)?;
+                }
+                // Switched here rather than with Command::uid, which switches before the
+                // pre_exec hooks run, so that root can still raise the limits above. The
+                // supplementary groups of the daemon are dropped like Command::uid does
+                if let Some(gid) = gid
+                    && libc::setgid(gid.as_raw()) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(uid) = uid {
+                    if libc::getuid() == 0 && libc::setgroups(0, std::ptr::null()) < 0 {
+                        return Err(std::io::Error::last_os_error());
                     }
-                    thread::sleep(Duration::from_secs(1));
+                    if libc::setuid(uid.as_raw()) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                if let Some(umask) = umask {
+                    libc::umask(umask);
                 }
+                if let Some(nice) = nice
+                    && libc::setpriority(libc::PRIO_PROCESS, 0, nice) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                // IOPRIO_WHO_PROCESS, of the calling process
+                if let Some(ioprio) = ioprio
+                    && libc::syscall(libc::SYS_ioprio_set, 1, 0, ioprio) < 0
+                {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    command
+}
+
+/// What the placeholders of --template-args stand for in a spawn of the child
+#[derive(Debug, Clone, Copy, Default)]
+struct Placeholders {
+    /// How often the child was restarted before this spawn
+    restart_count: u64,
+    /// The number of the spawn since the daemon started, from 1
+    instance: u64,
+}
+
+/// Replaces the `{restart_count}`, `{instance}` and `{daemon_pid}` placeholders in an
+/// argument, `{{` and `}}` stand for literal braces
+fn expand_placeholders(arg: &str, placeholders: &Placeholders) -> Result<String, String> {
+    let mut expanded = String::with_capacity(arg.len());
+    let mut rest = arg;
+    while let Some(pos) = rest.find(['{', '}']) {
+        expanded.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+        if let Some(after) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            expanded.push_str(&rest[..1]);
+            rest = after;
+            continue;
+        }
+        if rest.starts_with('}') {
+            return Err(format!(
+                "unmatched }} in {}, write }}}} for a literal }}",
+                arg
+            ));
+        }
+        let Some(end) = rest.find('}') else {
+            return Err(format!(
+                "unmatched {{ in {}, write {{{{ for a literal {{",
+                arg
+            ));
+        };
+        let value = match &rest[1..end] {
+            "restart_count" => placeholders.restart_count.to_string(),
+            "instance" => placeholders.instance.to_string(),
+            "daemon_pid" => unistd::getpid().to_string(),
+            name => {
+                return Err(format!(
+                    "unknown placeholder {{{}}} in {}, expected {{restart_count}}, {{instance}} \
+                     or {{daemon_pid}}",
+                    name, arg
+                ));
             }
+        };
+        expanded.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Spawns the child, which gets the sockets of --listen-fd and is sent the stop signal
+/// once the thread spawning it exits
+fn spawn_child(
+    spawner: &dyn ProcessSpawner,
+    args: &StartArgs,
+    child_config: &ChildConfig,
+    listen_fds: usize,
+    placeholders: &Placeholders,
+) -> std::io::Result<Box<dyn ChildProcess>> {
+    let stop_signal = args.stop_signal;
+    let stdin = child_config.stdin(args)?;
+    let argv = if args.template_args {
+        args.command
+            .iter()
+            .map(|arg| expand_placeholders(arg, placeholders))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(std::io::Error::other)?
+    } else {
+        args.command.clone()
+    };
+    let mut command = if listen_fds > 0 {
+        let mut command = build_command(&listen::command_line(&argv), args, child_config);
+        command.env("LISTEN_FDS", listen_fds.to_string());
+        command
+    } else {
+        build_command(&argv, args, child_config)
+    };
+    unsafe {
+        command.stdin(stdin).pre_exec(move || {
+            prctl(libc::PR_SET_PDEATHSIG, stop_signal as libc::c_int);
+            listen::inherit(listen_fds)
+        });
+    }
+    if let Some(pty) = &child_config.pty {
+        command
+            .stdin(pty.try_clone()?)
+            .stdout(pty.try_clone()?)
+            .stderr(pty.try_clone()?);
+        // Fails while a process left behind by the last child still holds on to it, the
+        // terminal works as the stdio of the child all the same
+        unsafe {
+            command.pre_exec(|| {
+                libc::ioctl(0, libc::TIOCSCTTY, 0);
+                Ok(())
+            });
         }
     }
+    spawner.spawn(&mut command)
+}
+
+/// Parses a `KEY=VALUE` pair, only the first `=` separates the key from the value
+fn parse_env_var(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("invalid KEY=VALUE: {}", s)),
+    }
+}
 
-    fn spawn_log_thread(
-        &self,
-        reader: impl Read + Send + 'static,
-        max_log_size: u64,
-    ) -> thread::JoinHandle<()> {
-        let running = self.running.clone();
-        let log_file = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&self.log_path)
-            .expect("Failed to open log file");
-
-        self.log_file.lock().unwrap().replace(
-            log_file
-                .try_clone()
-                .expect("Failed to clone log file handle"),
-        );
+/// Parses a `NAME=COMMAND` unit, the command is split into arguments like the shell does
+fn parse_unit(s: &str) -> Result<(String, Vec<String>), String> {
+    let (name, command) = s
+        .split_once('=')
+        .ok_or_else(|| format!("invalid NAME=COMMAND: {}", s))?;
+    validate_unit_name(name)?;
 
-        thread::spawn(move || {
-            let mut reader = reader;
-            let mut buf = [0; 4096];
-            let written_check = 1u64 << 20; // 1 MB
-            let mut bytes_written = 0;
-            let mut log_file = log_file;
+    let command = split_shell_words(command)?;
+    if command.is_empty() {
+        return Err(format!("missing command for unit {}", name));
+    }
+    Ok((name.to_string(), command))
+}
 
-            while running.load(std::sync::atomic::Ordering::Relaxed) {
-                match reader.read(&mut buf) {
-                    Ok(n) if n > 0 => {
-                        if bytes_written >= written_check {
-                            bytes_written = 0;
-                            if log_file
-                                .metadata()
-                                .expect("Failed to get log file metadata")
-                                .len()
-                                > max_log_size
-                            {
-                                log_file.set_len(0).expect("Failed to truncate log file");
-                                let msg = format!(
-                                    "[{}] Log size exceeded. Rotated",
-                                    Utc::now().to_rfc3339()
-                                );
-                                log_file
-                                    .write_all(msg.as_bytes())
-                                    .expect("Failed to write to log file");
-                            }
-                            log_file.flush().expect("Failed to flush log file");
-                        }
+/// Splits a command line into arguments on unquoted whitespace, honoring single and double
+/// quotes and backslash escapes like `sh` does, without expanding anything
+fn split_shell_words(s: &str) -> Result<Vec<String>, String> {
+    let mut words = Vec::new();
+    // None between words, so that a quoted empty string is still an argument
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
 
-                        log_file
-                            .write_all(&buf[..n])
-                            .expect("Failed to write to log file");
-                        bytes_written += n as u64;
-                    }
-                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
-                        break;
-                    }
-                    Err(err) => {
-                        eprintln!("Failed to read from pipe: {}", err);
-                        break;
+    while let Some(c) = chars.next() {
+        match c {
+            c if c.is_whitespace() => words.extend(word.take()),
+            '\'' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated single quote: {}", s)),
                     }
-                    Ok(_) => {
-                        // EOF
-                        break;
+                }
+            }
+            '"' => {
+                let word = word.get_or_insert_default();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // Inside double quotes, a backslash only escapes these
+                        Some('\\') => match chars.next() {
+                            Some(c @ ('"' | '\\' | '$' | '`')) => word.push(c),
+                            Some('\n') => {}
+                            Some(c) => {
+                                word.push('\\');
+                                word.push(c);
+                            }
+                            None => return Err(format!("unterminated double quote: {}", s)),
+                        },
+                        Some(c) => word.push(c),
+                        None => return Err(format!("unterminated double quote: {}", s)),
                     }
                 }
             }
-        })
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(c) => word.get_or_insert_default().push(c),
+                None => return Err(format!("trailing backslash: {}", s)),
+            },
+            c => word.get_or_insert_default().push(c),
+        }
     }
+    words.extend(word);
+    Ok(words)
+}
 
-    fn setup_signal_handler(&self) {
-        let running = self.running.clone();
-        let child_pid = self.child_pid.clone();
-        let log_file = self.log_file.clone();
-        ctrlc::set_handler(move || {
-            if let Some(pid) = child_pid.lock().unwrap().as_ref() {
-                _ = kill(*pid, nix::sys::signal::Signal::SIGTERM);
+/// Unit names end up in the PID file and in file names, so keep them simple
+fn validate_unit_name(name: &str) -> Result<(), String> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(format!(
+            "invalid unit name, only letters, digits, - and _ are allowed: {}",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Loads `KEY=VALUE` lines from a dotenv-style file, skipping blank lines and `#` comments.
+/// Values may be wrapped in single or double quotes
+fn load_env_file(path: &Path) -> Result<Vec<(String, String)>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read env file: {}", path.display()))?;
+
+    let mut vars = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (key, value) = parse_env_var(line).map_err(|err| {
+            anyhow::anyhow!("{}:{}: {}", path.display(), index + 1, err)
+        })?;
+        let value = value.trim();
+        let value = ['"', '\'']
+            .iter()
+            .find_map(|quote| {
+                value
+                    .strip_prefix(*quote)
+                    .and_then(|value| value.strip_suffix(*quote))
+            })
+            .unwrap_or(value);
+        vars.push((key.trim().to_string(), value.to_string()));
+    }
+    Ok(vars)
+}
+
+/// Reads the command of the child from a file, `-` being stdin. The file holds either a
+/// JSON array of strings or one argument per line, empty lines are skipped
+fn load_command_file(path: &Path) -> Result<Vec<String>> {
+    let content = if path == Path::new("-") {
+        std::io::read_to_string(std::io::stdin()).context("failed to read command from stdin")?
+    } else {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read command file: {}", path.display()))?
+    };
+
+    let command: Vec<String> = if content.trim_start().starts_with('[') {
+        serde_json::from_str(content.trim())
+            .with_context(|| format!("failed to parse command file: {}", path.display()))?
+    } else {
+        content
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(String::from)
+            .collect()
+    };
+
+    if command.is_empty() {
+        bail!(
+            "command file {} is empty, expected one argument per line or a JSON array",
+            path.display()
+        );
+    }
+    Ok(command)
+}
+
+fn should_restart(restart_on: RestartOn, restart_exit_codes: &[i32], status: ExitStatus) -> bool {
+    // An explicit list of exit codes takes precedence over the policy, a child killed
+    // by a signal has no exit code and falls back to the policy
+    if let Some(code) = status.code()
+        && !restart_exit_codes.is_empty()
+    {
+        return restart_exit_codes.contains(&code);
+    }
+
+    match restart_on {
+        RestartOn::Always => true,
+        RestartOn::OnFailure => status.signal().is_some() || status.code() != Some(0),
+        RestartOn::Never => false,
+    }
+}
+
+/// Reads the start time of a process in clock ticks since boot from /proc
+fn process_start_ticks(pid: Pid) -> Option<u64> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid.as_raw())).ok()?;
+    // The command name may contain spaces, so skip past its closing parenthesis;
+    // starttime is the 22nd field, the 20th after the command name
+    stat.rsplit_once(')')?
+        .1
+        .split_whitespace()
+        .nth(19)?
+        .parse()
+        .ok()
+}
+
+/// Finds the process holding the flock of a file in /proc/locks, whose lines look like
+/// `1: FLOCK  ADVISORY  WRITE 1234 fe:00:16172420 0 EOF`, the file given as
+/// `major:minor:inode` of its device
+fn lock_holder(path: &Path) -> Option<Pid> {
+    let metadata = std::fs::metadata(path).ok()?;
+    let dev = metadata.dev();
+    let file = format!(
+        "{:02x}:{:02x}:{}",
+        libc::major(dev),
+        libc::minor(dev),
+        metadata.ino()
+    );
+    let locks = std::fs::read_to_string("/proc/locks").ok()?;
+    locks.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.get(1) != Some(&"FLOCK") || fields.get(5) != Some(&file.as_str()) {
+            return None;
+        }
+        fields.get(4)?.parse().ok().map(Pid::from_raw)
+    })
+}
+
+/// Computes how long a process has been running from its start time in /proc
+fn process_uptime(pid: Pid) -> Option<Duration> {
+    let start_ticks = process_start_ticks(pid)?;
+
+    let system_uptime: f64 = std::fs::read_to_string("/proc/uptime")
+        .ok()?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+
+    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    if ticks_per_sec <= 0 {
+        return None;
+    }
+
+    let started_secs = start_ticks as f64 / ticks_per_sec as f64;
+    Some(Duration::from_secs_f64((system_uptime - started_secs).max(0.0)))
+}
+
+fn open_log_file(log_path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path)
+        .with_context(|| format!("failed to open log file: {}", log_path.display()))
+}
+
+/// The path of a compressed log generation, e.g. `stdout.log.1.gz`
+fn rotated_log_path(log_path: &Path, generation: u32) -> PathBuf {
+    let mut path = log_path.as_os_str().to_owned();
+    path.push(format!(".{}.gz", generation));
+    PathBuf::from(path)
+}
+
+/// Shifts the compressed generations up by one, dropping the oldest beyond `keep`,
+/// and moves the current log aside. Returns the path of the moved log along with the
+/// path it has to be compressed into, generation 1
+fn rotate_log(log_path: &Path, keep: u32) -> Result<(PathBuf, PathBuf)> {
+    let _ = std::fs::remove_file(rotated_log_path(log_path, keep));
+    for generation in (1..keep).rev() {
+        let from = rotated_log_path(log_path, generation);
+        if from.exists() {
+            std::fs::rename(&from, rotated_log_path(log_path, generation + 1))
+                .with_context(|| format!("failed to rename {}", from.display()))?;
+        }
+    }
+
+    let mut staged = log_path.as_os_str().to_owned();
+    staged.push(".1");
+    let staged = PathBuf::from(staged);
+    std::fs::rename(log_path, &staged)
+        .with_context(|| format!("failed to rename {}", log_path.display()))?;
+    Ok((staged, rotated_log_path(log_path, 1)))
+}
+
+/// Splits the name of a log rotated by `rotate_log_timestamped`, without the prefix and
+/// `.gz`, into its timestamp and the counter added to tell apart rotations within a second
+fn parse_rotation_stamp(stamp: &str) -> Option<(&str, u32)> {
+    let (time, counter) = match stamp.split_at_checked(15)? {
+        (time, "") => (time, 0),
+        (time, counter) => (time, counter.strip_prefix('-')?.parse().ok()?),
+    };
+    time.chars()
+        .all(|c| c.is_ascii_digit() || c == '-')
+        .then_some((time, counter))
+}
+
+/// Like `rotate_log`, but names the compressed log after the time of rotation, e.g.
+/// `stdout.log.20250101-000000.gz`, and removes the oldest such logs beyond `keep`. Another
+/// rotation within the same second gets a counter, e.g. `stdout.log.20250101-000000-1.gz`
+fn rotate_log_timestamped(log_path: &Path, keep: u32) -> Result<(PathBuf, PathBuf)> {
+    let dir = log_path.parent().unwrap_or(Path::new("."));
+    let prefix = format!(
+        "{}.",
+        log_path.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let mut rotated: Vec<((String, u32), PathBuf)> = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to list {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            let (time, counter) = name
+                .strip_prefix(&prefix)
+                .and_then(|name| name.strip_suffix(".gz"))
+                .and_then(parse_rotation_stamp)?;
+            Some(((time.to_string(), counter), entry.path()))
+        })
+        .collect();
+    // Counted past the rotations within the same second, even those about to be removed, so
+    // that the names keep sorting chronologically
+    let time = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+    let first = rotated
+        .iter()
+        .filter(|((rotated_time, _), _)| *rotated_time == time)
+        .map(|((_, counter), _)| counter + 1)
+        .max()
+        .unwrap_or(0);
+    let staged = (first..)
+        .map(|counter| {
+            let mut staged = log_path.as_os_str().to_owned();
+            match counter {
+                0 => staged.push(format!(".{}", time)),
+                _ => staged.push(format!(".{}-{}", time, counter)),
             }
-            running.store(false, std::sync::atomic::Ordering::Relaxed);
-            println!(
-                "[{}] Daemon: Received Ctrl-C, shutting down...",
-                Utc::now().to_rfc3339()
-            );
-            log_file
-                .lock()
-                .unwrap()
-                .as_mut()
-                .map(|f| f.sync_all().expect("Failed to sync log file"));
-            exit(0);
+            PathBuf::from(staged)
+        })
+        .find(|staged| {
+            let mut compressed = staged.as_os_str().to_owned();
+            compressed.push(".gz");
+            !staged.exists() && !Path::new(&compressed).exists()
         })
-        .expect("Failed to set Ctrl-C handler");
+        .expect("the counter runs until a free name");
+    // Sorted chronologically, the log about to be rotated takes one slot
+    rotated.sort();
+    let excess = (rotated.len() + 1).saturating_sub(keep as usize);
+    for (_, path) in &rotated[..excess.min(rotated.len())] {
+        let _ = std::fs::remove_file(path);
     }
 
-    fn status(&self) {
-        let (daemon_pid, child_pid) = self.get_pids().expect("Failed to get PIDs");
-        let is_child_running = is_process_exist(child_pid);
-        let is_daemon_running = is_process_exist(daemon_pid);
-        println!("Daemon PID: {}, running: {}", daemon_pid, is_daemon_running);
-        println!("Child PID: {}, running: {}", child_pid, is_child_running);
+    std::fs::rename(log_path, &staged)
+        .with_context(|| format!("failed to rename {}", log_path.display()))?;
+
+    let mut compressed = staged.as_os_str().to_owned();
+    compressed.push(".gz");
+    Ok((staged, PathBuf::from(compressed)))
+}
+
+fn compress_log(src: &Path, dst: &Path) -> Result<()> {
+    let mut input =
+        File::open(src).with_context(|| format!("failed to open {}", src.display()))?;
+    let output =
+        File::create(dst).with_context(|| format!("failed to create {}", dst.display()))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    std::io::copy(&mut input, &mut encoder)
+        .with_context(|| format!("failed to compress {}", src.display()))?;
+    encoder
+        .finish()
+        .with_context(|| format!("failed to finish {}", dst.display()))?;
+    std::fs::remove_file(src).with_context(|| format!("failed to remove {}", src.display()))?;
+    Ok(())
+}
+
+/// Returns the last `lines` lines of the content
+fn tail_lines(content: &[u8], lines: usize) -> &[u8] {
+    if lines == 0 {
+        return &[];
     }
+    // A trailing newline ends the last line rather than starting another one
+    let body = content.strip_suffix(b"\n").unwrap_or(content);
+    let start = body
+        .iter()
+        .enumerate()
+        .rev()
+        .filter(|&(_, &b)| b == b'\n')
+        .nth(lines.saturating_sub(1))
+        .map_or(0, |(pos, _)| pos + 1);
+    &content[start..]
 }
 
-#[derive(Parser, Debug)]
-#[command(version, about)]
-struct Cli {
-    #[command(subcommand)]
-    command: Commands,
+/// Writes a line prefixed with the current time, returning the number of bytes written
+fn write_timestamped_line(log_file: &mut File, line: &[u8]) -> std::io::Result<u64> {
+    let prefix = format!("[{}] ", Utc::now().to_rfc3339());
+    log_file.write_all(prefix.as_bytes())?;
+    log_file.write_all(line)?;
+    Ok((prefix.len() + line.len()) as u64)
 }
 
-#[derive(Subcommand, Debug)]
-enum Commands {
-    /// Start a new guard
-    Start(StartArgs),
-    /// Stop the guard
-    Stop,
-    /// Show the status of the guard
-    Status,
+/// A log file the output of the child is captured into, rotated when it grows too large
+/// and optionally on a schedule. Output is written unbuffered as soon as it is read from
+/// the pipe, so readers tailing the log see it without delay regardless of throughput
+struct LogWriter {
+    path: PathBuf,
+    file: File,
+    // Tracked on every write, so rotation doesn't depend on how bursty the output is
+    size: u64,
+    keep: u32,
+    // Rotated logs are named after the time of rotation instead of numbered
+    timestamped: bool,
+    // The handle used for syncing on shutdown, swapped on rotation
+    log_files: Arc<Mutex<Vec<File>>>,
+    log_slot: usize,
+    // Reports its failure once joined rather than logging it, as the rotation waiting for
+    // it may hold the lock of guarderd.log
+    compression: Option<thread::JoinHandle<Result<()>>>,
+    // Set while writes keep failing, so that only the first failure is reported
+    failing: bool,
 }
 
-#[derive(Args, Debug)]
-struct StartArgs {
-    /// The interval (in seconds) to restart the guard
-    #[arg(long, default_value_t = 5)]
-    restart_interval: u64,
+impl LogWriter {
+    fn open(
+        path: &Path,
+        keep: u32,
+        timestamped: bool,
+        log_files: Arc<Mutex<Vec<File>>>,
+    ) -> Result<Self> {
+        let file = open_log_file(path)?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        let log_slot = {
+            let mut log_files = log_files.lock().unwrap();
+            log_files.push(file.try_clone().context("failed to clone log file handle")?);
+            log_files.len() - 1
+        };
 
-    /// The command to run
-    #[arg(required = true, last = true)]
-    command: Vec<String>,
+        Ok(LogWriter {
+            path: path.to_path_buf(),
+            file,
+            size,
+            keep,
+            timestamped,
+            log_files,
+            log_slot,
+            compression: None,
+            failing: false,
+        })
+    }
 
-    /// The maximum size of the log file (in MiB)
-    #[arg(long, default_value_t = DEFAULT_MAX_LOG_SIZE_MIB)]
-    max_log_size_mib: u64,
+    /// Reports a failed operation on the log, returning whether it succeeded. A run of
+    /// failures is only reported once, a full disk would otherwise flood the daemon log.
+    /// Output that can't be written is dropped, the pipe keeps being drained so the child
+    /// never blocks on it
+    fn check<T>(&mut self, result: std::io::Result<T>, action: &str) -> Option<T> {
+        match result {
+            Ok(value) => {
+                self.failing = false;
+                Some(value)
+            }
+            Err(err) => {
+                if !self.failing {
+                    self.failing = true;
+                    log!(LogLevel::Error, "Failed to {} {}: {}", action, self.path.display(), err);
+                }
+                None
+            }
+        }
+    }
+
+    /// Moves the current log aside, or truncates it when no rotated logs are kept, and
+    /// starts the new one with `reason`
+    fn rotate(&mut self, reason: &str) {
+        if self.keep == 0 {
+            let result = self.file.set_len(0);
+            self.check(result, "truncate log file");
+        } else {
+            // Let the previous compression finish before shifting generations under it
+            join_compression(self.compression.take());
+            let rotated = if self.timestamped {
+                rotate_log_timestamped(&self.path, self.keep)
+            } else {
+                rotate_log(&self.path, self.keep)
+            };
+            match rotated.and_then(|(staged, compressed)| {
+                // Without a new log the output keeps going to the moved one, which is then
+                // left uncompressed
+                let file = open_log_file(&self.path)?;
+                Ok((file, staged, compressed))
+            }) {
+                Ok((file, staged, compressed)) => {
+                    self.file = file;
+                    match self.file.try_clone() {
+                        Ok(handle) => self.log_files.lock().unwrap()[self.log_slot] = handle,
+                        Err(err) => log!(
+                            LogLevel::Error,
+                            "Failed to clone log file handle: {}",
+                            err
+                        ),
+                    }
+                    self.compression =
+                        Some(thread::spawn(move || compress_log(&staged, &compressed)));
+                }
+                Err(err) => {
+                    log!(LogLevel::Error, "Failed to rotate log: {:#}", err);
+                    let result = self.file.set_len(0);
+                    self.check(result, "truncate log file");
+                }
+            }
+        }
+
+        let msg = format!("[{}] {}. Rotated\n", Utc::now().to_rfc3339(), reason);
+        let result = self.file.write_all(msg.as_bytes());
+        self.check(result, "write to log file");
+        self.size = msg.len() as u64;
+    }
 
-    /// The grace period (in seconds) to consider the child process started successfully
-    #[arg(long, default_value_t = 5)]
-    grace_period: u64,
+    /// Opens the log at its path again, e.g. after logrotate moved it away, so that the
+    /// output goes to a new file instead of the moved one
+    fn reopen(&mut self) {
+        let reopened = open_log_file(&self.path).and_then(|file| {
+            let size = file.metadata().context("failed to stat log file")?.len();
+            Ok((file, size))
+        });
+        match reopened {
+            Ok((file, size)) => {
+                self.file = file;
+                self.size = size;
+                match self.file.try_clone() {
+                    Ok(handle) => self.log_files.lock().unwrap()[self.log_slot] = handle,
+                    Err(err) => log!(LogLevel::Error, "Failed to clone log file handle: {}", err),
+                }
+                log!(LogLevel::Info, "Reopened log file {}", self.path.display());
+            }
+            Err(err) => log!(LogLevel::Error, "Failed to reopen log: {:#}", err),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        let result = self.file.write_all(buf);
+        if self.check(result, "write to log file").is_some() {
+            self.size += buf.len() as u64;
+        }
+    }
+
+    fn write_timestamped_line(&mut self, line: &[u8]) {
+        let result = write_timestamped_line(&mut self.file, line);
+        if let Some(written) = self.check(result, "write to log file") {
+            self.size += written;
+        }
+    }
+
+    /// Waits for a running compression, so it isn't cut short when the daemon exits
+    fn finish(self) {
+        join_compression(self.compression);
+    }
 }
 
-fn main() -> Result<()> {
-    let cli = Cli::parse();
-    let mut daemon = Daemon::new()?;
-    match cli.command {
-        Commands::Start(args) => {
-            daemon.start(
-                args.command,
-                Duration::from_secs(args.restart_interval),
-                args.max_log_size_mib,
-                Duration::from_secs(args.grace_period),
-            );
+/// Waits for the compression of a rotated log and reports whether it failed
+fn join_compression(compression: Option<thread::JoinHandle<Result<()>>>) {
+    if let Some(handle) = compression
+        && let Ok(Err(err)) = handle.join()
+    {
+        log!(LogLevel::Error, "Failed to compress log: {:#}", err);
+    }
+}
+
+/// Counts the lines written to a log for --log-max-lines-per-sec. Once the limit is reached
+/// the rest of the second's lines are only counted, and summed up when the second is over
+struct LineRateLimiter {
+    max_per_sec: u32,
+    second_start: Instant,
+    lines: u32,
+    suppressed: u64,
+}
+
+impl LineRateLimiter {
+    fn new(max_per_sec: u32) -> Self {
+        LineRateLimiter {
+            max_per_sec,
+            second_start: Instant::now(),
+            lines: 0,
+            suppressed: 0,
+        }
+    }
+
+    /// Whether a line may still be written in this second, counting it either way
+    fn allow(&mut self) -> bool {
+        self.lines = self.lines.saturating_add(1);
+        if self.lines > self.max_per_sec {
+            self.suppressed += 1;
+            return false;
         }
-        Commands::Stop => {
-            daemon.stop()?;
+        true
+    }
+
+    /// Starts a new second once the current one is over, or right away with `force`,
+    /// returning how many lines were suppressed in the one that ended
+    fn end_second(&mut self, force: bool) -> Option<u64> {
+        if !force && self.second_start.elapsed() < Duration::from_secs(1) {
+            return None;
         }
-        Commands::Status => {
-            daemon.status();
+        self.second_start = Instant::now();
+        self.lines = 0;
+        Some(std::mem::take(&mut self.suppressed)).filter(|&suppressed| suppressed > 0)
+    }
+}
+
+/// Duplicates stdout or stderr before it is redirected into a log pipe, for --tee
+fn dup_terminal(fd: impl AsFd) -> Result<File> {
+    let fd = fd
+        .as_fd()
+        .try_clone_to_owned()
+        .context("failed to duplicate the terminal")?;
+    Ok(File::from(fd))
+}
+
+/// Allocates the pseudo-terminal of --pty. Newlines are passed on as they are, rather than
+/// turned into CRLF, so the log reads the same as without it
+fn open_pty() -> Result<OpenptyResult> {
+    let winsize = Winsize {
+        ws_row: 24,
+        ws_col: 80,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    let pty = openpty(&winsize, None).context("failed to allocate a pseudo-terminal")?;
+    let mut termios = tcgetattr(&pty.slave).context("failed to get the terminal attributes")?;
+    termios.output_flags.remove(OutputFlags::ONLCR);
+    tcsetattr(&pty.slave, SetArg::TCSANOW, &termios)
+        .context("failed to set the terminal attributes")?;
+    Ok(pty)
+}
+
+/// Copies output to the `attach` clients as it is read, dropping the ones that detached or
+/// can't keep up so that they never hold up capturing the output
+fn write_attached(attached: &Mutex<Vec<UnixStream>>, data: &[u8]) {
+    attached
+        .lock()
+        .unwrap()
+        .retain_mut(|stream| stream.write_all(data).is_ok());
+}
+
+/// Writes a complete line of output to syslog, or to the log file without syslog or once
+/// sending to it failed
+fn write_line(
+    log: &mut LogWriter,
+    syslog: &mut Option<syslog::Syslog>,
+    timestamp_lines: bool,
+    line: &[u8],
+) {
+    if let Some(connection) = syslog {
+        match connection.send(line) {
+            Ok(()) => return,
+            Err(err) => {
+                log!(
+                    LogLevel::Error,
+                    "Failed to write to syslog, writing output to {} instead: {}",
+                    log.path.display(),
+                    err
+                );
+                *syslog = None;
+            }
         }
     }
+
+    if timestamp_lines {
+        log.write_timestamped_line(line);
+    } else {
+        log.write(line);
+    }
+}
+
+/// Waits up to `timeout` for the pipe to become readable, returning false on timeout
+fn wait_readable(fd: &impl AsRawFd, timeout: Option<Duration>) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let timeout_ms = timeout.map_or(-1, |timeout| {
+        timeout.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int
+    });
+    // Errors such as EINTR count as readable, the read that follows reports real ones
+    unsafe { libc::poll(&mut pollfd, 1, timeout_ms) != 0 }
+}
+
+/// Sets the capacity of a pipe, which the kernel rounds up to whole pages. Sizes past
+/// /proc/sys/fs/pipe-max-size need CAP_SYS_RESOURCE, the pipe keeps its size otherwise
+fn set_pipe_size(fd: &impl AsRawFd, size: usize) {
+    let size = size.min(libc::c_int::MAX as usize) as libc::c_int;
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETPIPE_SZ, size) } < 0 {
+        log!(
+            LogLevel::Warn,
+            "Failed to set the pipe buffer to {} bytes: {}",
+            size,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+/// Makes writes to the fd fail with WouldBlock instead of waiting for room
+fn set_nonblocking(fd: &impl AsRawFd) -> std::io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_GETFL) };
+    if flags < 0
+        || unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+    {
+        return Err(std::io::Error::last_os_error());
+    }
     Ok(())
 }
+
+/// Where the relay of --log-overflow drop is within the output of the child
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RelayState {
+    /// Writing, `mid_line` once part of a line is written and the rest is still to come
+    Writing { mid_line: bool },
+    /// Dropping the rest of a line that didn't fit, `unterminated` if its start was written
+    /// and the log is still owed its newline
+    Dropping { unterminated: bool },
+    /// At the start of a line, with the newline of a line cut short still to be written
+    Terminating,
+}
+
+/// Writes what the relay read to the non-blocking log pipe, in pieces of whole lines of at
+/// most PIPE_BUF bytes, which a pipe takes whole or not at all. A line that doesn't fit is
+/// dropped up to its newline, so that no two lines are spliced together. Returns how many
+/// bytes were dropped
+fn relay_lines(
+    writer: &mut impl Write,
+    mut data: &[u8],
+    state: &mut RelayState,
+) -> std::io::Result<u64> {
+    let mut try_write = |piece: &[u8]| match writer.write(piece) {
+        Ok(written) => Ok(written),
+        Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => Ok(0),
+        Err(err) => Err(err),
+    };
+    let mut dropped = 0;
+    while !data.is_empty() {
+        match *state {
+            RelayState::Writing { mid_line } => {
+                let limit = data.len().min(libc::PIPE_BUF);
+                // A longer line is written in pieces of PIPE_BUF bytes
+                let len = data[..limit]
+                    .iter()
+                    .rposition(|&b| b == b'\n')
+                    .map_or(limit, |pos| pos + 1);
+                let mut written = try_write(&data[..len])?;
+                // The first line may still fit when all of them don't
+                if written == 0
+                    && let Some(pos) = data[..len - 1].iter().position(|&b| b == b'\n')
+                {
+                    written = try_write(&data[..=pos])?;
+                }
+                match written {
+                    0 => {
+                        *state = RelayState::Dropping {
+                            unterminated: mid_line,
+                        }
+                    }
+                    written => {
+                        *state = RelayState::Writing {
+                            mid_line: data[written - 1] != b'\n',
+                        };
+                        data = &data[written..];
+                    }
+                }
+            }
+            RelayState::Dropping { unterminated } => {
+                let Some(pos) = data.iter().position(|&b| b == b'\n') else {
+                    dropped += data.len() as u64;
+                    break;
+                };
+                dropped += pos as u64 + 1;
+                data = &data[pos + 1..];
+                *state = if unterminated {
+                    RelayState::Terminating
+                } else {
+                    RelayState::Writing { mid_line: false }
+                };
+            }
+            // Without room for the newline the next line is dropped as well
+            RelayState::Terminating => match try_write(b"\n")? {
+                0 => *state = RelayState::Dropping { unterminated: true },
+                _ => *state = RelayState::Writing { mid_line: false },
+            },
+        }
+    }
+    Ok(dropped)
+}
+
+/// Seconds since the epoch of the next multiple of `interval`, so that daily logs are
+/// rotated at midnight UTC
+fn next_rotation_time(interval: Duration) -> u64 {
+    let now = Utc::now().timestamp().max(0) as u64;
+    let interval = interval.as_secs().max(1);
+    (now / interval + 1) * interval
+}
+
+/// Waits up to LOG_DRAIN_TIMEOUT for the log threads to finish once `running` is false, so
+/// that the output left in the pipes makes it into the logs
+fn join_log_threads(threads: &Mutex<Vec<thread::JoinHandle<()>>>) {
+    let threads = std::mem::take(&mut *threads.lock().unwrap());
+    let deadline = Instant::now() + LOG_DRAIN_TIMEOUT;
+    while Instant::now() < deadline && !threads.iter().all(thread::JoinHandle::is_finished) {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    for handle in threads {
+        if handle.is_finished() {
+            let _ = handle.join();
+        } else {
+            log!(
+                LogLevel::Warn,
+                "Log thread still busy after {} seconds, the remaining output is lost",
+                LOG_DRAIN_TIMEOUT.as_secs()
+            );
+        }
+    }
+}
+
+fn make_shared_counter() -> &'static AtomicU64 {
+    let addr = unsafe {
+        mmap64(
+            std::ptr::null_mut(),
+            std::mem::size_of::<AtomicU64>(),
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        )
+    };
+
+    if addr == libc::MAP_FAILED {
+        panic!("mmap64 failed");
+    }
+
+    let counter = unsafe { &mut *(addr as *mut AtomicU64) };
+    counter.store(0, std::sync::atomic::Ordering::SeqCst);
+    
+    counter
+}
+
+/// The settings of a running guard, persisted in the PID file after the PIDs so that
+/// other subcommands know what is being supervised. Each setting is a `key: value` line:
+///
+/// ```text
+/// command: ["python","my_script.py"]
+/// restart_interval: 5s
+/// restart_on: always
+/// restart_exit_codes: 1,2,75
+/// max_restarts: 0
+/// max_log_size_mib: 10
+/// log_keep: 5
+/// stop_signal: SIGTERM
+/// started_at: 2025-01-01T00:00:00+00:00
+/// cgroup: /sys/fs/cgroup/guarderd/web
+/// unit_command.worker: ["python","worker.py"]
+/// ```
+///
+/// The command is a JSON array of strings so arguments may contain any character, each
+/// named unit adds a `unit_command.<name>` line.
+/// Unknown keys are ignored and missing ones take their defaults, so PID files written
+/// by other versions remain readable.
+#[derive(Debug, Clone)]
+struct RunInfo {
+    command: Vec<String>,
+    restart_interval: Duration,
+    restart_on: RestartOn,
+    restart_exit_codes: Vec<i32>,
+    max_restarts: u64,
+    max_log_size_mib: u64,
+    log_keep: u32,
+    stop_signal: Signal,
+    // When the guard was started, in RFC 3339 like the timestamps in the logs
+    started_at: Option<String>,
+    units: BTreeMap<String, Vec<String>>,
+    // The cgroup the processes were placed in, to kill what's left of them on stop
+    cgroup: Option<PathBuf>,
+}
+
+impl RunInfo {
+    fn from_args(args: &StartArgs, config: &Config) -> Self {
+        RunInfo {
+            command: args.command.clone(),
+            restart_interval: args.restart_interval(config),
+            restart_on: args.restart_on,
+            restart_exit_codes: args.restart_exit_codes.clone(),
+            max_restarts: args.max_restarts,
+            max_log_size_mib: args.max_log_size_mib(config),
+            log_keep: args.log_keep,
+            stop_signal: args.stop_signal,
+            started_at: Some(Utc::now().to_rfc3339()),
+            units: args.units.iter().cloned().collect(),
+            cgroup: None,
+        }
+    }
+
+    fn parse(content: &str) -> Result<Self> {
+        let mut run_info = RunInfo {
+            command: Vec::new(),
+            restart_interval: DEFAULT_RESTART_INTERVAL,
+            restart_on: RestartOn::Always,
+            restart_exit_codes: Vec::new(),
+            max_restarts: 0,
+            max_log_size_mib: DEFAULT_MAX_LOG_SIZE_MIB,
+            log_keep: DEFAULT_LOG_KEEP,
+            stop_signal: Signal::SIGTERM,
+            started_at: None,
+            units: BTreeMap::new(),
+            cgroup: None,
+        };
+
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            let context = || format!("failed to parse {}: {}", key.trim(), value);
+
+            match key.trim() {
+                "command" => {
+                    run_info.command = serde_json::from_str(value).with_context(context)?
+                }
+                // Plain numbers of seconds were written before durations took units
+                "restart_interval" => {
+                    run_info.restart_interval = parse_duration(value)
+                        .map_err(anyhow::Error::msg)
+                        .with_context(context)?
+                }
+                "restart_on" => {
+                    run_info.restart_on = RestartOn::from_str(value, true)
+                        .map_err(anyhow::Error::msg)
+                        .with_context(context)?
+                }
+                "restart_exit_codes" => {
+                    run_info.restart_exit_codes = value
+                        .split(',')
+                        .filter(|code| !code.is_empty())
+                        .map(|code| code.trim().parse())
+                        .collect::<Result<_, _>>()
+                        .with_context(context)?
+                }
+                "max_restarts" => run_info.max_restarts = value.parse().with_context(context)?,
+                "max_log_size_mib" => {
+                    run_info.max_log_size_mib = value.parse().with_context(context)?
+                }
+                "log_keep" => run_info.log_keep = value.parse().with_context(context)?,
+                "stop_signal" => {
+                    run_info.stop_signal = parse_signal(value)
+                        .map_err(anyhow::Error::msg)
+                        .with_context(context)?
+                }
+                "started_at" => run_info.started_at = Some(value.to_string()),
+                "cgroup" => run_info.cgroup = Some(PathBuf::from(value)),
+                key if key.starts_with("unit_command.") => {
+                    let name = key.trim_start_matches("unit_command.");
+                    let command = serde_json::from_str(value).with_context(context)?;
+                    run_info.units.insert(name.to_string(), command);
+                }
+                _ => {
+                    // Ignore unknown keys for forward compatibility
+                }
+            }
+        }
+
+        if run_info.command.is_empty() {
+            bail!("command not found in PID file");
+        }
+        Ok(run_info)
+    }
+}
+
+impl std::fmt::Display for RunInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let command = serde_json::to_string(&self.command).map_err(|_| std::fmt::Error)?;
+        let restart_on = self
+            .restart_on
+            .to_possible_value()
+            .expect("no skipped variants");
+        let restart_exit_codes: Vec<String> = self
+            .restart_exit_codes
+            .iter()
+            .map(|code| code.to_string())
+            .collect();
+
+        writeln!(f, "command: {}", command)?;
+        writeln!(
+            f,
+            "restart_interval: {}",
+            format_duration(self.restart_interval)
+        )?;
+        writeln!(f, "restart_on: {}", restart_on.get_name())?;
+        writeln!(f, "restart_exit_codes: {}", restart_exit_codes.join(","))?;
+        writeln!(f, "max_restarts: {}", self.max_restarts)?;
+        writeln!(f, "max_log_size_mib: {}", self.max_log_size_mib)?;
+        writeln!(f, "log_keep: {}", self.log_keep)?;
+        writeln!(f, "stop_signal: {}", self.stop_signal)?;
+        if let Some(started_at) = &self.started_at {
+            writeln!(f, "started_at: {}", started_at)?;
+        }
+        if let Some(cgroup) = &self.cgroup {
+            writeln!(f, "cgroup: {}", cgroup.display())?;
+        }
+        for (name, command) in &self.units {
+            let command = serde_json::to_string(command).map_err(|_| std::fmt::Error)?;
+            writeln!(f, "unit_command.{}: {}", name, command)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the output of the child is written to the log files
+#[derive(Debug, Clone, Copy)]
+struct LogOptions {
+    keep: u32,
+    timestamp_lines: bool,
+    rotate_interval: Option<Duration>,
+    buffer_size: usize,
+    overflow: LogOverflow,
+    max_lines_per_sec: u32,
+    flush_interval: Duration,
+}
+
+/// The shared state of the daemon the reload thread updates
+struct ReloadHandle {
+    running: Arc<AtomicBool>,
+    child_pid: Arc<Mutex<Option<Pid>>>,
+    restart_interval_ms: Arc<AtomicU64>,
+    max_log_size: Arc<AtomicU64>,
+    overlap_requested: Arc<AtomicBool>,
+    spawner: Arc<dyn ProcessSpawner>,
+}
+
+/// Settings read from the `--config` TOML file, named like the flags of start:
+///
+/// ```toml
+/// command = ["python", "my_script.py"]
+/// restart_interval = "10s"
+/// max_log_size_mib = 50
+/// restart_on = "on-failure"
+/// stop_signal = "SIGINT"
+///
+/// [env]
+/// RUST_LOG = "info"
+///
+/// [units]
+/// worker = ["python", "worker.py"]
+/// ```
+///
+/// Flags given to start take precedence over the file. The file is read again on SIGHUP,
+/// `restart_interval` and `max_log_size_mib` are then applied to the running guard.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    command: Option<Vec<String>>,
+    shell: Option<bool>,
+    template_args: Option<bool>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    restart_interval: Option<Duration>,
+    max_log_size_mib: Option<u64>,
+    log_keep: Option<u32>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    log_rotate_interval: Option<Duration>,
+    truncate_log_on_start: Option<bool>,
+    log_buffer_kib: Option<usize>,
+    log_max_lines_per_sec: Option<u32>,
+    log_overflow: Option<LogOverflow>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    log_flush_interval: Option<Duration>,
+    journal_max_size_mib: Option<u64>,
+    journal_keep: Option<u32>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    grace_period: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    start_delay: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    lock_wait: Option<Duration>,
+    backoff: Option<bool>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    max_backoff: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    restart_jitter: Option<Duration>,
+    max_restarts: Option<u64>,
+    restart_limit: Option<u32>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    restart_window: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    min_uptime: Option<Duration>,
+    restart_on: Option<RestartOn>,
+    once: Option<bool>,
+    stop_on_success: Option<bool>,
+    restart_exit_codes: Option<Vec<i32>>,
+    foreground: Option<bool>,
+    tee: Option<bool>,
+    exit_with_parent: Option<bool>,
+    separate_stderr: Option<bool>,
+    pty: Option<bool>,
+    timestamp_lines: Option<bool>,
+    syslog: Option<bool>,
+    syslog_facility: Option<syslog::Facility>,
+    syslog_tag: Option<String>,
+    stop_signal: Option<String>,
+    post_spawn_signal: Option<String>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    post_spawn_delay: Option<Duration>,
+    env: Option<BTreeMap<String, String>>,
+    env_clear: Option<bool>,
+    env_passthrough: Option<Vec<String>>,
+    env_file: Option<PathBuf>,
+    workdir: Option<PathBuf>,
+    stdin: Option<StdinSource>,
+    pidfile_format: Option<PidFileFormat>,
+    stdin_file: Option<PathBuf>,
+    user: Option<String>,
+    group: Option<String>,
+    limit_as: Option<u64>,
+    limit_nofile: Option<u64>,
+    limit_cpu: Option<u64>,
+    nice: Option<i32>,
+    ionice: Option<String>,
+    cgroup: Option<PathBuf>,
+    cgroup_set: Option<BTreeMap<String, String>>,
+    umask: Option<String>,
+    daemon_umask: Option<String>,
+    socket_mode: Option<String>,
+    health_url: Option<String>,
+    health_tcp: Option<String>,
+    health_cmd: Option<String>,
+    heartbeat_file: Option<PathBuf>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    heartbeat_timeout: Option<Duration>,
+    pre_start: Option<String>,
+    post_stop: Option<String>,
+    hooks_fatal: Option<bool>,
+    stages: Option<Vec<String>>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    health_interval: Option<Duration>,
+    health_failures: Option<u32>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    health_start_delay: Option<Duration>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    ready_timeout: Option<Duration>,
+    hup_restarts_child: Option<bool>,
+    overlap_restart: Option<bool>,
+    watch: Option<Vec<PathBuf>>,
+    #[serde(deserialize_with = "deserialize_duration")]
+    watch_debounce: Option<Duration>,
+    sd_notify: Option<bool>,
+    notify_webhook: Option<String>,
+    metrics_addr: Option<String>,
+    units: Option<BTreeMap<String, Vec<String>>>,
+    listen_fds: Option<Vec<String>>,
+}
+
+/// Reads a duration of the config file, a string such as "30s" like the flags take or a
+/// plain number of seconds
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    match Option::<toml::Value>::deserialize(deserializer)? {
+        Some(toml::Value::Integer(secs)) if secs >= 0 => Ok(Some(Duration::from_secs(secs as u64))),
+        Some(toml::Value::String(text)) => parse_duration(&text)
+            .map(Some)
+            .map_err(serde::de::Error::custom),
+        Some(value) => Err(serde::de::Error::custom(format!(
+            "invalid duration, expected e.g. \"30s\" or a number of seconds, found {}",
+            value.type_str()
+        ))),
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file: {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse config file: {}", path.display()))
+    }
+}
+
+/// The live state of the guard mirrored into `state.json` for external monitors
+#[derive(Debug, Clone, Default)]
+struct StateSnapshot {
+    state: &'static str,
+    daemon_pid: Option<Pid>,
+    child_pid: Option<Pid>,
+    child_started_at: Option<String>,
+    restarts: u64,
+    last_exit_code: Option<i32>,
+    last_exit_status: Option<String>,
+    // Only kept for the placeholders of --overlap-restart, not written to state.json
+    generation: u64,
+    dropped_log_bytes: u64,
+    suppressed_log_lines: u64,
+    log_rotations: u64,
+    // The latest rotations as (time, log file, reason), the oldest first
+    log_rotation_history: VecDeque<(String, String, String)>,
+}
+
+impl StateSnapshot {
+    fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "state": self.state,
+            "daemon_pid": self.daemon_pid.map(|pid| pid.as_raw()),
+            "child_pid": self.child_pid.map(|pid| pid.as_raw()),
+            "child_started_at": self.child_started_at.clone(),
+            "restarts": self.restarts,
+            "last_exit_code": self.last_exit_code,
+            "last_exit_status": self.last_exit_status.clone(),
+            "dropped_log_bytes": self.dropped_log_bytes,
+            "suppressed_log_lines": self.suppressed_log_lines,
+            "log_rotations": self.log_rotations,
+            "log_rotation_history": self
+                .log_rotation_history
+                .iter()
+                .map(|(at, log, reason)| {
+                    serde_json::json!({ "at": at, "log": log, "reason": reason })
+                })
+                .collect::<Vec<_>>(),
+            "updated_at": Utc::now().to_rfc3339(),
+        })
+    }
+}
+
+/// Writes `state.json` through a temporary file and a rename, so a reader never sees a
+/// half-written file
+fn write_state_json(path: &Path, snapshot: &StateSnapshot) -> Result<()> {
+    let content =
+        serde_json::to_string_pretty(&snapshot.to_json()).context("failed to serialize state")?;
+    replace_file(path, &content)
+}
+
+/// Replaces a file through a temporary file and a rename, so a reader never sees it
+/// half-written
+fn replace_file(path: &Path, content: &str) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    std::fs::write(&tmp_path, content)
+        .with_context(|| format!("failed to write {}", tmp_path.display()))?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("failed to rename {}", tmp_path.display()))?;
+    Ok(())
+}
+
+/// The status recorded for a child that couldn't be spawned or waited for, the 127 a shell
+/// exits with when it can't run a command
+fn failed_status() -> ExitStatus {
+    ExitStatus::from_raw(127 << 8)
+}
+
+/// Waits for a child to exit, returning what it used. Should waiting fail, the child is
+/// killed so that it isn't left running unsupervised, and it counts as failed
+fn wait_child(child: &mut dyn ChildProcess) -> (ExitStatus, Option<ResourceUsage>) {
+    match child.wait() {
+        Ok((status, usage)) => (status, Some(usage)),
+        Err(err) => {
+            log!(
+                LogLevel::Error,
+                "Failed to wait for process {}: {}",
+                child.id(),
+                err
+            );
+            let _ = child.kill();
+            (failed_status(), None)
+        }
+    }
+}
+
+/// Clean exits are part of the lifecycle, anything else is a crash worth a warning
+fn exit_level(status: ExitStatus) -> LogLevel {
+    if status.success() {
+        LogLevel::Info
+    } else {
+        LogLevel::Warn
+    }
+}
+
+/// Joins a command for display, quoting the arguments a shell would split or interpret
+/// so that it can be copied and pasted
+fn quote_command(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| {
+            let plain = !arg.is_empty()
+                && arg
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_./=:,+@%".contains(c));
+            if plain {
+                arg.clone()
+            } else {
+                format!("'{}'", arg.replace('\'', "'\\''"))
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Formats a duration the way it is given to the options, e.g. `500ms` or `30s`
+fn format_duration(duration: Duration) -> String {
+    if duration.subsec_millis() == 0 {
+        format!("{}s", duration.as_secs())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+/// A random duration from zero up to `max` in whole milliseconds. Without randomness from
+/// the kernel it's zero, leaving out the jitter rather than failing the restart
+fn random_duration(max: Duration) -> Duration {
+    let mut bytes = [0u8; 8];
+    let read = unsafe { libc::getrandom(bytes.as_mut_ptr().cast(), bytes.len(), 0) };
+    if read != bytes.len() as isize {
+        return Duration::ZERO;
+    }
+    let millis = max.as_millis() as u64;
+    Duration::from_millis(u64::from_ne_bytes(bytes) % (millis + 1))
+}
+
+/// Formats an uptime for people, e.g. `2d 3h 4m 5s`, leaving out the leading zero units
+fn format_uptime(uptime: Duration) -> String {
+    let secs = uptime.as_secs();
+    let parts = [
+        (secs / 86400, "d"),
+        (secs / 3600 % 24, "h"),
+        (secs / 60 % 60, "m"),
+        (secs % 60, "s"),
+    ];
+    let first = parts[..3].iter().position(|&(value, _)| value > 0).unwrap_or(3);
+    parts[first..]
+        .iter()
+        .map(|(value, unit)| format!("{}{}", value, unit))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Describes how a process exited, e.g. `exited with code 1` or `killed by SIGSEGV`
+fn describe_exit(status: ExitStatus) -> String {
+    if let Some(code) = status.code() {
+        return format!("exited with code {}", code);
+    }
+    let Some(signal) = status.signal() else {
+        return status.to_string();
+    };
+
+    let name = Signal::try_from(signal)
+        .map(|signal| signal.to_string())
+        .unwrap_or_else(|_| format!("signal {}", signal));
+    if status.core_dumped() {
+        format!("killed by {} (core dumped)", name)
+    } else {
+        format!("killed by {}", name)
+    }
+}
+
+/// Restart statistics persisted in the status dir across daemon runs
+#[derive(Debug, Default)]
+struct Stats {
+    restarts: u64,
+    last_exit_code: Option<i32>,
+    // The signal that killed the child, when it didn't exit by itself
+    last_exit_signal: Option<i32>,
+    // Why the child last exited, see describe_exit()
+    last_exit: Option<String>,
+    // The CPU time and peak memory of the last run, to spot a child that leaks until it is
+    // OOM-killed
+    last_usage: Option<ResourceUsage>,
+    last_restart: Option<String>,
+}
+
+impl Stats {
+    fn parse(content: &str) -> Option<Self> {
+        let mut stats = Stats::default();
+        let mut usage = ResourceUsage::default();
+        let mut has_usage = false;
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "restarts" => stats.restarts = value.parse().ok()?,
+                "last_exit_code" if value != "none" => {
+                    stats.last_exit_code = Some(value.parse().ok()?)
+                }
+                "last_exit_signal" if value != "none" => {
+                    stats.last_exit_signal = Some(value.parse().ok()?)
+                }
+                "last_exit" if value != "none" => stats.last_exit = Some(value.to_string()),
+                "last_restart" if value != "none" => stats.last_restart = Some(value.to_string()),
+                "last_user_cpu_secs" if value != "none" => {
+                    usage.user_time = Duration::try_from_secs_f64(value.parse().ok()?).ok()?;
+                    has_usage = true;
+                }
+                "last_system_cpu_secs" if value != "none" => {
+                    usage.system_time = Duration::try_from_secs_f64(value.parse().ok()?).ok()?;
+                }
+                "last_max_rss_kib" if value != "none" => usage.max_rss_kib = value.parse().ok()?,
+                _ => {}
+            }
+        }
+        stats.last_usage = has_usage.then_some(usage);
+        Some(stats)
+    }
+
+    fn record_exit(&mut self, status: ExitStatus, usage: Option<ResourceUsage>) {
+        self.last_exit_code = status.code();
+        self.last_exit_signal = status.signal();
+        self.last_exit = Some(describe_exit(status));
+        self.last_usage = usage;
+    }
+
+    /// The exit code of the last exit as a shell reports it, 128 plus the signal number
+    /// when the child was killed by one
+    fn shell_exit_code(&self) -> Option<u8> {
+        match (self.last_exit_code, self.last_exit_signal) {
+            (Some(code), _) => Some(code as u8),
+            (None, Some(signal)) => Some(128u8.wrapping_add(signal as u8)),
+            (None, None) => None,
+        }
+    }
+
+    /// What the daemon exits with when supervision ends with the last exit of the child, so
+    /// that whoever started it sees how the child failed
+    fn daemon_exit_code(&self) -> ExitCode {
+        self.shell_exit_code()
+            .map_or(ExitCode::FAILURE, ExitCode::from)
+    }
+
+    fn record_restart(&mut self) {
+        self.restarts += 1;
+        self.last_restart = Some(Utc::now().to_rfc3339());
+    }
+}
+
+impl std::fmt::Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "restarts: {}", self.restarts)?;
+        match self.last_exit_code {
+            Some(code) => writeln!(f, "last_exit_code: {}", code)?,
+            None => writeln!(f, "last_exit_code: none")?,
+        }
+        match self.last_exit_signal {
+            Some(signal) => writeln!(f, "last_exit_signal: {}", signal)?,
+            None => writeln!(f, "last_exit_signal: none")?,
+        }
+        writeln!(f, "last_exit: {}", self.last_exit.as_deref().unwrap_or("none"))?;
+        match &self.last_usage {
+            Some(usage) => {
+                let (user, system) = (usage.user_time, usage.system_time);
+                writeln!(f, "last_user_cpu_secs: {:.3}", user.as_secs_f64())?;
+                writeln!(f, "last_system_cpu_secs: {:.3}", system.as_secs_f64())?;
+                writeln!(f, "last_max_rss_kib: {}", usage.max_rss_kib)?;
+            }
+            None => {
+                writeln!(f, "last_user_cpu_secs: none")?;
+                writeln!(f, "last_system_cpu_secs: none")?;
+                writeln!(f, "last_max_rss_kib: none")?;
+            }
+        }
+        writeln!(
+            f,
+            "last_restart: {}",
+            self.last_restart.as_deref().unwrap_or("none")
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Daemon {
+    launch_dir: PathBuf,
+    status_dir: PathBuf,
+    pid_file: PathBuf,
+    // Set with --pid-file, keeps the other files of guards sharing a status dir apart
+    file_prefix: String,
+    pid_file_format: PidFileFormat,
+    // What the PID file holds in the keyvalue format, when it holds the child PID only
+    run_info_file: PathBuf,
+    child_pid: Arc<Mutex<Option<Pid>>>,
+    // When the current child was spawned, recorded before its PID
+    child_spawned_at: Arc<Mutex<Option<SystemTime>>>,
+    // The current PID of each named unit, also guarding writes of the PID file
+    unit_pids: Arc<Mutex<BTreeMap<String, Pid>>>,
+    log_path: PathBuf,
+    stderr_log_path: PathBuf,
+    // The messages of the daemon itself
+    daemon_log_path: PathBuf,
+    log_files: Arc<Mutex<Vec<File>>>,
+    log_threads: Arc<Mutex<Vec<thread::JoinHandle<()>>>>,
+    // The connections of `attach` clients the output of the child is copied to
+    attached: Arc<Mutex<Vec<UnixStream>>>,
+    lock_file: PathBuf,
+    lock_handle: Option<File>,
+    state_file: PathBuf,
+    stats_file: PathBuf,
+    stop_signal_file: PathBuf,
+    state_json_file: PathBuf,
+    control_socket: PathBuf,
+    journal_path: PathBuf,
+    state_snapshot: Arc<Mutex<StateSnapshot>>,
+    running: Arc<AtomicBool>,
+    // Cleared once the child exited on shutdown, the log threads then drain the pipes
+    capturing: Arc<AtomicBool>,
+    // Set by the health thread when it stops a child that didn't become ready in time
+    ready_failed: Arc<AtomicBool>,
+    // Set by a reload with --overlap-restart, the overlap thread picks it up
+    overlap_requested: Arc<AtomicBool>,
+    // Everything the supervision spawns and signals goes through it
+    spawner: Arc<dyn ProcessSpawner>,
+    // Times the uptimes of the child and the waits between restarts
+    clock: Arc<dyn Clock>,
+    // The cgroup of --cgroup the child and units are moved into
+    cgroup: Option<cgroup::Cgroup>,
+    // Hot-reloadable settings, replaced on SIGHUP
+    restart_interval_ms: Arc<AtomicU64>,
+    max_log_size: Arc<AtomicU64>,
+}
+
+impl Daemon {
+    fn new(status_dir: Option<PathBuf>, files: StatusFiles) -> Result<Self> {
+        let current_dir = std::env::current_dir().context("fail to current dir")?;
+        let status_dir = current_dir.join(status_dir.unwrap_or_else(|| PathBuf::from(STATUS_PATH)));
+        std::fs::create_dir_all(&status_dir).context("fail to create status dir")?;
+
+        // Relative overrides are resolved inside the status dir, absolute ones replace it
+        let pid_file = status_dir.join(files.pid_file.as_deref().unwrap_or(Path::new("pid")));
+        let lock_file = status_dir.join(files.lock_file.as_deref().unwrap_or(Path::new("lock")));
+        let log_path =
+            status_dir.join(files.log_file.as_deref().unwrap_or(Path::new("stdout.log")));
+
+        // The remaining files live next to the PID file, named after it when it was given
+        let file_prefix = files
+            .pid_file
+            .as_deref()
+            .and_then(Path::file_stem)
+            .map(|stem| format!("{}.", stem.to_string_lossy()))
+            .unwrap_or_default();
+        let runtime_file = |name: &str| pid_file.with_file_name(format!("{}{}", file_prefix, name));
+        let stderr_log_path = runtime_file("stderr.log");
+        let daemon_log_path = runtime_file("guarderd.log");
+        let state_file = runtime_file("state");
+        let stats_file = runtime_file("stats");
+        let stop_signal_file = runtime_file("stop_signal");
+        let state_json_file = runtime_file("state.json");
+        let control_socket = runtime_file("control.sock");
+        let journal_path = runtime_file("events.jsonl");
+        let run_info_file = runtime_file("run_info");
+
+        Ok(Daemon {
+            launch_dir: current_dir,
+            status_dir,
+            pid_file,
+            file_prefix,
+            pid_file_format: PidFileFormat::KeyValue,
+            run_info_file,
+            child_pid: Arc::new(None.into()),
+            child_spawned_at: Arc::new(None.into()),
+            unit_pids: Arc::new(Mutex::new(BTreeMap::new())),
+            log_path,
+            stderr_log_path,
+            daemon_log_path,
+            lock_file,
+            log_files: Arc::new(Mutex::new(Vec::new())),
+            log_threads: Arc::new(Mutex::new(Vec::new())),
+            attached: Arc::new(Mutex::new(Vec::new())),
+            lock_handle: None,
+            state_file,
+            stats_file,
+            stop_signal_file,
+            state_json_file,
+            control_socket,
+            journal_path,
+            state_snapshot: Arc::new(Mutex::new(StateSnapshot::default())),
+            running: Arc::new(AtomicBool::new(false)),
+            capturing: Arc::new(AtomicBool::new(true)),
+            ready_failed: Arc::new(AtomicBool::new(false)),
+            overlap_requested: Arc::new(AtomicBool::new(false)),
+            spawner: Arc::new(OsSpawner::default()),
+            clock: Arc::new(SystemClock),
+            cgroup: None,
+            restart_interval_ms: Arc::new(AtomicU64::new(
+                DEFAULT_RESTART_INTERVAL.as_millis() as u64
+            )),
+            max_log_size: Arc::new(AtomicU64::new(DEFAULT_MAX_LOG_SIZE_MIB << 20)),
+        })
+    }
+
+    /// Takes the lock of the status dir, retrying for up to `wait` while another daemon,
+    /// e.g. one that is still shutting down, holds it
+    fn try_lock(&mut self, wait: Option<Duration>) -> Result<()> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.lock_file)
+            .with_context(|| format!("failed to open lock file: {}", self.lock_file.display()))?;
+
+        let started = Instant::now();
+        let mut next_progress = started;
+        while let Err(err) = file.try_lock_exclusive() {
+            let contended = err.kind() == fs2::lock_contended_error().kind();
+            let Some(wait) = wait.filter(|&wait| contended && started.elapsed() < wait) else {
+                if contended {
+                    bail!(Failure::LockHeld(format!(
+                        "failed to acquire lock {}, {}",
+                        self.lock_file.display(),
+                        self.describe_lock_holder()
+                    )));
+                }
+                bail!(Failure::LockHeld(format!(
+                    "failed to acquire lock, the daemon may already be running: {}",
+                    err
+                )));
+            };
+
+            if Instant::now() >= next_progress {
+                log!(
+                    LogLevel::Info,
+                    "Lock {} is held, waiting up to {} more seconds for it",
+                    self.lock_file.display(),
+                    wait.saturating_sub(started.elapsed()).as_secs_f64().ceil()
+                );
+                next_progress += LOCK_PROGRESS_INTERVAL;
+            }
+            thread::sleep(LOCK_POLL_INTERVAL);
+        }
+
+        self.lock_handle = Some(file);
+
+        Ok(())
+    }
+
+    /// Who holds the lock going by the PID file, which the daemon holding it has usually
+    /// written, and by /proc/locks
+    fn describe_lock_holder(&self) -> String {
+        let holder = match lock_holder(&self.lock_file) {
+            Some(pid) => format!("process {}", pid),
+            None => "another process".to_string(),
+        };
+        let Ok((daemon_pid, _)) = self.get_pids() else {
+            return format!(
+                "it is held by {} but {} doesn't exist, a guard may be starting up",
+                holder,
+                self.pid_file.display()
+            );
+        };
+        match daemon_pid.state() {
+            ProcessState::Running => format!("daemon {} is already running", daemon_pid),
+            // A lock outlives its daemon only in a process that inherited it, or it belongs
+            // to a guard with another PID file sharing the lock file
+            ProcessState::Exited => format!(
+                "it is held by {} but daemon {} of {} is not running. The lock is stale, \
+                 held by a process the daemon left behind or a guard using another --pid-file",
+                holder,
+                daemon_pid,
+                self.pid_file.display()
+            ),
+            ProcessState::Unconfirmed => format!(
+                "it is held by {} but PID {} of {} no longer belongs to the daemon. The lock \
+                 is stale, held by a process the daemon left behind or a guard using another \
+                 --pid-file",
+                holder,
+                daemon_pid,
+                self.pid_file.display()
+            ),
+        }
+    }
+
+    /// Removes the PID file of the previous daemon, so that `status` doesn't report its dead
+    /// processes until the new daemon writes its own. Only with the lock held, which rules
+    /// out a running daemon of the status dir, and once the recorded daemon is gone or its
+    /// PID was reused. A control socket left next to it means the daemon died without
+    /// cleaning up, e.g. on SIGKILL, which is worth a warning
+    fn remove_stale_pid_file(&self) {
+        let Ok((daemon_pid, _)) = self.get_pids() else {
+            return;
+        };
+        if daemon_pid.state() == ProcessState::Running {
+            return;
+        }
+        for path in [&self.pid_file, &self.run_info_file] {
+            if let Err(err) = std::fs::remove_file(path)
+                && err.kind() != std::io::ErrorKind::NotFound
+            {
+                log!(
+                    LogLevel::Warn,
+                    "Failed to remove stale PID file {}: {}",
+                    path.display(),
+                    err
+                );
+                return;
+            }
+        }
+        if std::fs::remove_file(&self.control_socket).is_ok() {
+            log!(
+                LogLevel::Warn,
+                "Recovered stale state of daemon {}, which exited without cleaning up",
+                daemon_pid
+            );
+        }
+    }
+
+    /// Writes the PIDs of the daemon, the child and every unit followed by the run info:
+    ///
+    /// ```text
+    /// daemon_pid: 1234
+    /// daemon_started: 4567890
+    /// child_pid: 1235
+    /// child_started: 4567990
+    /// unit_pid.worker: 1236
+    /// unit_started.worker: 4567991
+    /// command: ["python","my_script.py"]
+    /// ...
+    /// ```
+    ///
+    /// The `*_started` lines hold the start time of each process from /proc, which tells
+    /// it apart from a later process reusing the PID
+    fn save_pids(&self, daemon_pid: Pid, run_info: &RunInfo) -> Result<()> {
+        let child_pid = *self.child_pid.lock().unwrap();
+        let unit_pids = self.unit_pids.lock().unwrap();
+
+        let mut content = String::new();
+        let mut push_pid = |pid_key: &str, started_key: &str, pid: Pid| {
+            content.push_str(&format!("{}: {}\n", pid_key, pid.as_raw()));
+            if let Some(start_ticks) = process_start_ticks(pid) {
+                content.push_str(&format!("{}: {}\n", started_key, start_ticks));
+            }
+        };
+        push_pid("daemon_pid", "daemon_started", daemon_pid);
+        if let Some(child_pid) = child_pid {
+            push_pid("child_pid", "child_started", child_pid);
+        }
+        for (name, pid) in unit_pids.iter() {
+            push_pid(
+                &format!("unit_pid.{}", name),
+                &format!("unit_started.{}", name),
+                *pid,
+            );
+        }
+        content.push_str(&run_info.to_string());
+
+        match self.pid_file_format {
+            PidFileFormat::KeyValue => replace_file(&self.pid_file, &content),
+            // The PID file is only there while a child is
+            PidFileFormat::Simple => {
+                replace_file(&self.run_info_file, &content)?;
+                match child_pid {
+                    Some(child_pid) => {
+                        replace_file(&self.pid_file, &format!("{}\n", child_pid.as_raw()))
+                    }
+                    None => match std::fs::remove_file(&self.pid_file) {
+                        Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                            Err(err).context("failed to remove PID file")
+                        }
+                        _ => Ok(()),
+                    },
+                }
+            }
+        }
+    }
+
+    /// The PID file in the keyvalue format. A PID file of the simple format, holding just
+    /// the child PID, or none at all while the child is down, stands for the run info file
+    fn read_pid_file(&self) -> Result<String> {
+        let pid_file = match std::fs::read_to_string(&self.pid_file) {
+            Ok(content) => Some(content),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("failed to read PID file: {}", self.pid_file.display())
+                });
+            }
+        };
+        let simple = pid_file
+            .as_deref()
+            .is_none_or(|content| content.trim().parse::<i32>().is_ok());
+        if !simple {
+            return Ok(pid_file.unwrap_or_default());
+        }
+        match std::fs::read_to_string(&self.run_info_file) {
+            Ok(content) => Ok(content),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound && pid_file.is_none() => {
+                bail!(Failure::DaemonNotRunning(format!(
+                    "PID file does not exist: {}",
+                    self.pid_file.display()
+                )))
+            }
+            Err(err) => Err(err).with_context(|| {
+                format!("failed to read run info: {}", self.run_info_file.display())
+            }),
+        }
+    }
+
+    fn get_pids(&self) -> Result<(TrackedPid, TrackedPid)> {
+        let content = self.read_pid_file()?;
+
+        let mut daemon_pid: Option<i32> = None;
+        let mut child_pid: Option<i32> = None;
+        let mut daemon_started: Option<u64> = None;
+        let mut child_started: Option<u64> = None;
+
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+
+            if let Some((key, value)) = line.split_once(':') {
+                let key = key.trim();
+                let value = value.trim();
+
+                match key {
+                    "daemon_pid" => {
+                        daemon_pid =
+                            Some(value.parse::<i32>().with_context(|| {
+                                format!("failed to parse daemon_pid: {}", value)
+                            })?);
+                    }
+                    "child_pid" => {
+                        child_pid =
+                            Some(value.parse::<i32>().with_context(|| {
+                                format!("failed to parse child_pid: {}", value)
+                            })?);
+                    }
+                    "daemon_started" => daemon_started = value.parse().ok(),
+                    "child_started" => child_started = value.parse().ok(),
+                    _ => {
+                        // Ignore unknown keys for forward compatibility
+                    }
+                }
+            }
+        }
+
+        let daemon_pid =
+            daemon_pid.ok_or_else(|| anyhow::anyhow!("daemon_pid not found in PID file"))?;
+        let child_pid =
+            child_pid.ok_or_else(|| anyhow::anyhow!("child_pid not found in PID file"))?;
+
+        Ok((
+            TrackedPid {
+                pid: Pid::from_raw(daemon_pid),
+                start_ticks: daemon_started,
+            },
+            TrackedPid {
+                pid: Pid::from_raw(child_pid),
+                start_ticks: child_started,
+            },
+        ))
+    }
+
+    /// Returns the PID of each named unit recorded in the PID file
+    fn get_unit_pids(&self) -> Result<BTreeMap<String, TrackedPid>> {
+        let content = self.read_pid_file()?;
+
+        let mut unit_pids = BTreeMap::new();
+        let mut unit_started = BTreeMap::new();
+        for line in content.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            if let Some(name) = key.trim().strip_prefix("unit_pid.") {
+                let pid = value
+                    .parse::<i32>()
+                    .with_context(|| format!("failed to parse PID of unit {}: {}", name, value))?;
+                unit_pids.insert(name.to_string(), Pid::from_raw(pid));
+            } else if let Some(name) = key.trim().strip_prefix("unit_started.")
+                && let Ok(start_ticks) = value.parse::<u64>()
+            {
+                unit_started.insert(name.to_string(), start_ticks);
+            }
+        }
+
+        Ok(unit_pids
+            .into_iter()
+            .map(|(name, pid)| {
+                let start_ticks = unit_started.get(&name).copied();
+                (name, TrackedPid { pid, start_ticks })
+            })
+            .collect())
+    }
+
+    /// Returns the PID of a named unit, failing if the guard has no such unit
+    fn get_unit_pid(&self, name: &str) -> Result<TrackedPid> {
+        self.get_unit_pids()?
+            .remove(name)
+            .with_context(|| format!("no such unit: {}", name))
+    }
+
+    /// The marker `stop --unit` leaves behind so the daemon doesn't respawn the unit
+    fn unit_stop_file(&self, name: &str) -> PathBuf {
+        self.pid_file.with_file_name(format!("{}unit.{}.stopped", self.file_prefix, name))
+    }
+
+    /// The cgroup from the `cgroup:` line, read on its own so that `stop` doesn't depend on
+    /// the rest of the PID file
+    fn get_cgroup(&self) -> Result<Option<PathBuf>> {
+        let content = self.read_pid_file()?;
+        Ok(content.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == "cgroup").then(|| PathBuf::from(value.trim()))
+        }))
+    }
+
+    fn get_run_info(&self) -> Result<RunInfo> {
+        let content = self.read_pid_file()?;
+        RunInfo::parse(&content)
+    }
+
+    fn save_gave_up(&self, restarts: u64) -> Result<()> {
+        let content = format!(
+            "state: gave_up\nrestarts: {}\ntimestamp: {}\n",
+            restarts,
+            Utc::now().to_rfc3339()
+        );
+        std::fs::write(&self.state_file, content).context("failed to write state file")?;
+        Ok(())
+    }
+
+    /// Returns the (restarts, timestamp) recorded when the daemon gave up, if it did
+    fn get_gave_up(&self) -> Option<(String, String)> {
+        let content = std::fs::read_to_string(&self.state_file).ok()?;
+
+        let mut gave_up = false;
+        let mut restarts = None;
+        let mut timestamp = None;
+
+        for line in content.lines() {
+            if let Some((key, value)) = line.split_once(':') {
+                let value = value.trim().to_string();
+                match key.trim() {
+                    "state" => gave_up = value == "gave_up",
+                    "restarts" => restarts = Some(value),
+                    "timestamp" => timestamp = Some(value),
+                    _ => {}
+                }
+            }
+        }
+
+        if !gave_up {
+            return None;
+        }
+        Some((restarts?, timestamp?))
+    }
+
+    /// Loads the restart statistics, a missing or malformed file reads as zeros
+    fn load_stats(&self) -> Stats {
+        std::fs::read_to_string(&self.stats_file)
+            .ok()
+            .and_then(|content| Stats::parse(&content))
+            .unwrap_or_default()
+    }
+
+    fn save_stats(&self, stats: &Stats) -> Result<()> {
+        std::fs::write(&self.stats_file, stats.to_string()).context("failed to write stats file")?;
+        Ok(())
+    }
+
+    /// Sends `signal` to the child only, so that the daemon respawns it [default: the stop
+    /// signal given to start]
+    fn restart_child(&self, signal: Option<Signal>) -> Result<()> {
+        let (daemon_pid, child_pid) = self.get_pids()?;
+        if !daemon_pid.is_running() {
+            bail!(Failure::DaemonNotRunning(format!(
+                "Daemon {} is not running",
+                daemon_pid
+            )));
+        }
+
+        let run_info = self.get_run_info()?;
+        let signal = signal.unwrap_or(run_info.stop_signal);
+        // The daemon knows its current child for sure, the PID file is only a fallback
+        let command = format!("restart-child {}", signal);
+        let child_pid = match control::request(&self.control_socket, &command)? {
+            Some(pid) => pid
+                .parse()
+                .map(Pid::from_raw)
+                .with_context(|| format!("unexpected child PID from daemon: {}", pid))?,
+            None => {
+                if run_info.restart_on == RestartOn::Never {
+                    bail!(
+                        "The restart policy of daemon {} is never, the child would not be respawned",
+                        daemon_pid
+                    );
+                }
+                child_pid
+                    .kill_group(signal)
+                    .with_context(|| format!("failed to send {} to child {}", signal, child_pid))?;
+                child_pid.pid
+            }
+        };
+
+        println!(
+            "Sent {} to child {} ({}), daemon {} will respawn it in {}",
+            signal,
+            child_pid,
+            quote_command(&run_info.command),
+            daemon_pid,
+            format_duration(run_info.restart_interval)
+        );
+        Ok(())
+    }
+
+    /// Makes the daemon re-read its config file, like sending it SIGHUP
+    fn reload(&self) -> Result<()> {
+        let (daemon_pid, _) = self.get_pids()?;
+        if control::request(&self.control_socket, "reload")?.is_none() {
+            if !daemon_pid.is_running() {
+                bail!(Failure::DaemonNotRunning(format!(
+                    "Daemon {} is not running",
+                    daemon_pid
+                )));
+            }
+            daemon_pid
+                .kill(Signal::SIGHUP)
+                .with_context(|| format!("failed to send SIGHUP to daemon {}", daemon_pid))?;
+        }
+
+        println!("Asked daemon {} to reload its config", daemon_pid);
+        Ok(())
+    }
+
+    /// Sends a signal to one unit, or to the child and every unit when none is given. The
+    /// daemon sends it when it answers, so that only access to its socket is needed
+    fn signal(&self, signal: Signal, unit: Option<&str>) -> Result<()> {
+        let command = match unit {
+            Some(name) => format!("signal {} {}", signal, name),
+            None => format!("signal {}", signal),
+        };
+        if let Some(reply) = control::request(&self.control_socket, &command)? {
+            let signaled: serde_json::Value =
+                serde_json::from_str(&reply).context("unexpected reply from daemon")?;
+            if let Some(pid) = signaled.get("child").and_then(|pid| pid.as_i64()) {
+                println!("Sent {} to child process {}", signal, pid);
+            }
+            if let Some(units) = signaled.get("units").and_then(|units| units.as_object()) {
+                for (name, pid) in units {
+                    println!("Sent {} to unit {} process {}", signal, name, pid);
+                }
+            }
+            return Ok(());
+        }
+
+        if let Some(name) = unit {
+            let pid = self.get_unit_pid(name)?;
+            if pid.state() == ProcessState::Exited {
+                bail!("Unit {} process {} is not running", name, pid);
+            }
+
+            pid.kill(signal)
+                .with_context(|| format!("failed to send {} to unit {} {}", signal, name, pid))?;
+            println!("Sent {} to unit {} process {}", signal, name, pid);
+            return Ok(());
+        }
+
+        let (_, child_pid) = self.get_pids()?;
+        if child_pid.state() == ProcessState::Exited {
+            bail!("Child process {} is not running", child_pid);
+        }
+
+        child_pid
+            .kill(signal)
+            .with_context(|| format!("failed to send {} to child {}", signal, child_pid))?;
+        println!("Sent {} to child process {}", signal, child_pid);
+
+        for (name, pid) in self.get_unit_pids()? {
+            if !pid.is_running() {
+                println!("Unit {} process {} is not running, skipped", name, pid);
+                continue;
+            }
+            pid.kill(signal)
+                .with_context(|| format!("failed to send {} to unit {} {}", signal, name, pid))?;
+            println!("Sent {} to unit {} process {}", signal, name, pid);
+        }
+        Ok(())
+    }
+
+    fn stop(
+        &self,
+        daemon_timeout: Duration,
+        stop_timeout: Duration,
+        stop_signal: Option<Signal>,
+    ) -> Result<()> {
+        let (daemon_pid, child_pid) = self.get_pids()?;
+
+        let command = match stop_signal {
+            Some(stop_signal) => format!("stop {}", stop_signal),
+            None => "stop".to_string(),
+        };
+        if control::request(&self.control_socket, &command)?.is_none() {
+            match daemon_pid.state() {
+                ProcessState::Running => {}
+                ProcessState::Exited => bail!(Failure::DaemonNotRunning(format!(
+                    "Daemon {} is not running",
+                    daemon_pid
+                ))),
+                ProcessState::Unconfirmed => bail!(
+                    "PID {} no longer belongs to the daemon, refusing to stop it. \
+                     Remove {} if the daemon is gone",
+                    daemon_pid,
+                    self.pid_file.display()
+                ),
+            }
+
+            // The daemon signals the child when it shuts down, tell it which signal to use
+            if let Some(stop_signal) = stop_signal {
+                std::fs::write(&self.stop_signal_file, stop_signal.as_str())
+                    .context("failed to write stop signal file")?;
+            }
+
+            daemon_pid
+                .kill(nix::sys::signal::Signal::SIGTERM)
+                .with_context(|| format!("failed to send SIGTERM to daemon {}", daemon_pid))?;
+        }
+
+        // The daemon exits once the child did, after running --post-stop. A child that
+        // outlasts the stop timeout is killed, the daemon then gets its own timeout
+        let start = std::time::Instant::now();
+        while start.elapsed() < stop_timeout && daemon_pid.is_running() && child_pid.is_running() {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        if daemon_pid.is_running() && child_pid.is_running() {
+            if log_enabled(LogLevel::Warn) {
+                println!(
+                    "Child process {} is still running after {}, killing it",
+                    child_pid,
+                    format_duration(stop_timeout)
+                );
+            }
+            child_pid
+                .kill_group(nix::sys::signal::Signal::SIGKILL)
+                .with_context(|| format!("failed to send SIGKILL to child {}", child_pid))?;
+        }
+        let start = std::time::Instant::now();
+        while start.elapsed() < daemon_timeout && daemon_pid.is_running() {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+
+        if daemon_pid.is_running() {
+            if log_enabled(LogLevel::Warn) {
+                println!(
+                    "Daemon {} is still running {} after SIGTERM, sending SIGKILL",
+                    daemon_pid,
+                    format_duration(daemon_timeout)
+                );
+            }
+            daemon_pid
+                .kill(nix::sys::signal::Signal::SIGKILL)
+                .with_context(|| format!("failed to send SIGKILL to daemon {}", daemon_pid))?;
+        }
+
+        // wait for child exit, with stop_timeout
+        // if the child process is still running after that, kill it with SIGKILL
+        if log_enabled(LogLevel::Info) {
+            println!(
+                "Stopped daemon {}, waiting for child {} to exit",
+                daemon_pid, child_pid
+            );
+        }
+        wait_or_kill("Child process", child_pid, stop_timeout)?;
+
+        // The daemon sends the units the stop signal too, they get the same timeout
+        for (name, pid) in self.get_unit_pids()? {
+            wait_or_kill(&format!("Unit {} process", name), pid, stop_timeout)?;
+        }
+
+        // Whatever the processes forked and left behind in their cgroup goes with them
+        if let Some(path) = self.get_cgroup()? {
+            let cgroup = cgroup::Cgroup::open(&path);
+            let left = cgroup.processes()?;
+            if !left.is_empty() {
+                if log_enabled(LogLevel::Warn) {
+                    println!(
+                        "Killing {} processes left in cgroup {}",
+                        left.len(),
+                        path.display()
+                    );
+                }
+                cgroup.kill()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Stops a single unit, the daemon and its other processes keep running
+    fn stop_unit(
+        &self,
+        name: &str,
+        stop_timeout: Duration,
+        stop_signal: Option<Signal>,
+    ) -> Result<()> {
+        let (daemon_pid, _) = self.get_pids()?;
+        let pid = self.get_unit_pid(name)?;
+        if !daemon_pid.is_running() {
+            bail!(Failure::DaemonNotRunning(format!(
+                "Daemon {} is not running",
+                daemon_pid
+            )));
+        }
+        if pid.state() == ProcessState::Exited {
+            println!("Unit {} process {} is not running", name, pid);
+            return Ok(());
+        }
+
+        let stop_signal = match stop_signal {
+            Some(stop_signal) => stop_signal,
+            None => self.get_run_info()?.stop_signal,
+        };
+
+        // Tells the daemon not to respawn the unit once it exits
+        std::fs::write(self.unit_stop_file(name), "")
+            .context("failed to write unit stop file")?;
+        pid.kill_group(stop_signal)
+            .inspect_err(|_| {
+                let _ = std::fs::remove_file(self.unit_stop_file(name));
+            })
+            .with_context(|| format!("failed to send {} to unit {} {}", stop_signal, name, pid))?;
+
+        println!("Sent {} to unit {} process {}, waiting for it to exit", stop_signal, name, pid);
+        wait_or_kill(&format!("Unit {} process", name), pid, stop_timeout)
+    }
+
+    /// Copies the live output of the child to stdout until the daemon exits. Ctrl-C only
+    /// ends this process, the daemon drops the connection on its next write
+    fn attach(&self) -> Result<()> {
+        let (daemon_pid, _) = self.get_pids()?;
+        let Some(mut output) = control::attach(&self.control_socket)? else {
+            bail!(Failure::DaemonNotRunning(format!(
+                "Daemon {} is not running",
+                daemon_pid
+            )));
+        };
+        // Notices go to stderr, so that stdout only carries the output of the child
+        if log_enabled(LogLevel::Info) {
+            eprintln!("Attached to daemon {}, press Ctrl-C to detach", daemon_pid);
+        }
+
+        let mut stdout = std::io::stdout().lock();
+        let mut buf = [0; 4096];
+        loop {
+            let n = output.read(&mut buf).context("failed to read output")?;
+            if n == 0 {
+                break;
+            }
+            stdout.write_all(&buf[..n])?;
+            stdout.flush()?;
+        }
+
+        if log_enabled(LogLevel::Info) {
+            eprintln!("Daemon {} exited", daemon_pid);
+        }
+        Ok(())
+    }
+
+    /// Has the daemon probe the child once with its health check, the same probe that
+    /// decides on restarts, and prints the outcome
+    fn health(&self) -> Result<ExitCode> {
+        let (daemon_pid, _) = self.get_pids()?;
+        let Some(report) = control::request(&self.control_socket, "health")? else {
+            bail!(Failure::DaemonNotRunning(format!(
+                "Daemon {} is not running",
+                daemon_pid
+            )));
+        };
+        println!("{}", report);
+
+        let report: serde_json::Value =
+            serde_json::from_str(&report).context("unexpected health report from daemon")?;
+        if report.get("healthy").and_then(|healthy| healthy.as_bool()) == Some(true) {
+            Ok(ExitCode::SUCCESS)
+        } else {
+            Ok(ExitCode::FAILURE)
+        }
+    }
+
+    /// Blocks until the current child exits or the daemon stops, returning the exit code
+    /// the child last exited with. A child that already exited returns right away
+    fn wait(&self, timeout: Option<Duration>) -> Result<ExitCode> {
+        let (daemon_pid, child_pid) = self.get_pids()?;
+        let stats_modified = || {
+            std::fs::metadata(&self.stats_file)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        };
+        let last_recorded = stats_modified();
+        let started = Instant::now();
+
+        let was_running = child_pid.is_running() && daemon_pid.is_running();
+        while child_pid.is_running() && daemon_pid.is_running() {
+            if let Some(timeout) = timeout
+                && started.elapsed() >= timeout
+            {
+                bail!(Failure::Timeout(format!(
+                    "Child process {} is still running after {}",
+                    child_pid,
+                    format_duration(timeout)
+                )));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        // The daemon records the exit right after reaping the child, give it a moment
+        if was_running {
+            let recorded_by = Instant::now() + Duration::from_secs(1);
+            while stats_modified() == last_recorded
+                && daemon_pid.is_running()
+                && Instant::now() < recorded_by
+            {
+                thread::sleep(Duration::from_millis(20));
+            }
+        }
+
+        let stats = self.load_stats();
+        let Some(code) = stats.shell_exit_code() else {
+            bail!("No exit of child process {} was recorded", child_pid);
+        };
+        println!(
+            "Child process {} {}",
+            child_pid,
+            stats.last_exit.as_deref().unwrap_or("exited")
+        );
+        Ok(ExitCode::from(code))
+    }
+
+    /// Prints the last lines of the captured output, and with `follow` keeps printing
+    /// whatever the daemon appends, like `tail -F`
+    fn logs(&self, lines: usize, follow: bool) -> Result<()> {
+        let open = || {
+            File::open(&self.log_path)
+                .with_context(|| format!("failed to open log file: {}", self.log_path.display()))
+        };
+        let mut log_file = open()?;
+        let mut content = Vec::new();
+        log_file
+            .read_to_end(&mut content)
+            .context("failed to read log file")?;
+
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(tail_lines(&content, lines))?;
+        stdout.flush()?;
+        if !follow {
+            return Ok(());
+        }
+
+        let mut position = content.len() as u64;
+        let mut inode = log_file.metadata()?.ino();
+        loop {
+            let copied = std::io::copy(&mut log_file, &mut stdout)?;
+            position += copied;
+            if copied > 0 {
+                stdout.flush()?;
+                continue;
+            }
+            thread::sleep(Duration::from_millis(200));
+
+            match std::fs::metadata(&self.log_path) {
+                // Rotated, the daemon writes to a new file once the old one is moved aside
+                Ok(metadata) if metadata.ino() != inode => {
+                    std::io::copy(&mut log_file, &mut stdout)?;
+                    log_file = open()?;
+                    inode = log_file.metadata()?.ino();
+                    position = 0;
+                }
+                // Truncated in place, which is how logs are rotated with --log-keep 0
+                Ok(metadata) if metadata.len() < position => {
+                    log_file.seek(SeekFrom::Start(0))?;
+                    position = 0;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn wait_for_child_grace_period(&self, cnt: &AtomicU64, grace_deadline: Instant) {
+        while Instant::now() < grace_deadline {
+            if cnt.load(std::sync::atomic::Ordering::SeqCst) > 0 {
+                log!(LogLevel::Error, "Child process exited in grace period, startup failed");
+                std::process::exit(1);
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        // Printed on the terminal, the daemon log is only opened by the forked daemon
+        log!(LogLevel::Info, "Start successful, exiting grace period");
+    }
+
+    /// Supervises the child until the guard shuts down, returning the exit code of the
+    /// daemon, the one of the child with --once
+    fn start(&mut self, args: StartArgs, config: Config) -> Result<ExitCode> {
+        self.apply_config(&args, &config);
+        if let Some(umask) = args.daemon_umask {
+            unsafe { libc::umask(umask) };
+        }
+
+        let mut run_info = RunInfo::from_args(&args, &config);
+        let max_backoff = args.backoff.then_some(args.max_backoff);
+        let grace_period = args.grace_period;
+        let stop_signal = args.stop_signal;
+
+        // Resolved before forking so that mistakes are reported on the terminal
+        let mut child_config = ChildConfig::resolve(&args, &self.launch_dir)?;
+        let watch_paths: Vec<PathBuf> = args
+            .watch
+            .iter()
+            .map(|path| self.launch_dir.join(path))
+            .collect();
+        if let Some(path) = watch_paths.iter().find(|path| !path.exists()) {
+            bail!("watched path does not exist: {}", path.display());
+        }
+        let path = child_config.path(&args);
+        resolve_program(&args.command[0], &child_config.workdir, path.as_deref())
+            .map_err(|err| Failure::CommandNotFound(err.to_string()))?;
+        for (name, command) in &args.units {
+            resolve_program(&command[0], &child_config.workdir, path.as_deref())
+                .map_err(|err| Failure::CommandNotFound(format!("unit {}: {}", name, err)))?;
+        }
+
+        if args.dry_run {
+            return self
+                .dry_run(&args, &child_config, &run_info)
+                .map(|()| ExitCode::SUCCESS);
+        }
+
+        // Before binding, so that a second start reports the held lock rather than the
+        // address in use. The sockets then take over fds 3 and up, which the lock file
+        // makes way for, before anything else is opened
+        self.try_lock(args.lock_wait)?;
+        if let Some(lock) = self.lock_handle.take() {
+            self.lock_handle = Some(listen::make_way(lock, &args.listen_fds)?);
+        }
+        let listen_fds = listen::open(&args.listen_fds)?;
+        self.remove_stale_pid_file();
+        self.pid_file_format = args.pidfile_format;
+
+        if let Some(path) = &args.cgroup {
+            self.cgroup = cgroup::Cgroup::create(path, &args.cgroup_set)?;
+            match &self.cgroup {
+                Some(cgroup) => {
+                    run_info.cgroup = Some(cgroup.path().to_path_buf());
+                    child_config.cgroup_procs = Some(cgroup.procs_file()?);
+                }
+                None => log!(
+                    LogLevel::Warn,
+                    "cgroup v2 is not mounted, running without --cgroup {}",
+                    path.display()
+                ),
+            }
+        }
+
+        let journal = journal::Journal::open(
+            &self.journal_path,
+            args.journal_max_size_mib << 20,
+            args.journal_keep,
+        )?;
+        let _ = JOURNAL.set(Mutex::new(journal));
+
+        // A state file left behind by a previous run no longer applies
+        let _ = std::fs::remove_file(&self.state_file);
+        let _ = std::fs::remove_file(&self.stop_signal_file);
+
+        if args.truncate_log_on_start {
+            for path in [&self.log_path, &self.stderr_log_path] {
+                if let Err(err) = OpenOptions::new().write(true).truncate(true).open(path)
+                    && err.kind() != std::io::ErrorKind::NotFound
+                {
+                    return Err(err)
+                        .with_context(|| format!("failed to truncate {}", path.display()));
+                }
+            }
+        }
+
+        let metrics_listener = args.metrics_addr.as_deref().map(metrics::listen).transpose()?;
+
+        let cnt = make_shared_counter();
+        // The grace period starts with the first spawn
+        let grace_deadline = Instant::now() + args.start_delay.unwrap_or_default() + grace_period;
+        // Taken before daemonizing, which reparents the daemon
+        let parent = TrackedPid {
+            pid: unistd::getppid(),
+            start_ticks: process_start_ticks(unistd::getppid()),
+        };
+
+        let daemon_pid = if args.foreground {
+            unistd::getpid()
+        } else {
+            daemonize(args.trace, || {
+                self.wait_for_child_grace_period(cnt, grace_deadline)
+            })
+            .context("failed to daemonize")?
+        };
+
+        self.running
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+        record_event(
+            "daemon_start",
+            serde_json::json!({
+                "pid": daemon_pid.as_raw(),
+                "command": args.command.clone(),
+            }),
+        );
+
+        self.setup_signal_handler(args.stop_signal)?;
+        self.spawn_reload_thread(&args);
+
+        // In foreground mode the output stays attached to the terminal, with --tee it's
+        // copied there from the log threads
+        if !args.foreground || args.tee {
+            let (stdout_tee, stderr_tee) = if args.tee {
+                (
+                    Some(dup_terminal(std::io::stdout())?),
+                    Some(dup_terminal(std::io::stderr())?),
+                )
+            } else {
+                (None, None)
+            };
+
+            if args.foreground {
+                // The messages of the daemon stay on the terminal rather than going into
+                // the redirected stdout
+                let _ = DAEMON_LOG.set(DaemonLog::Terminal(dup_terminal(std::io::stdout())?));
+            } else {
+                let log = LogWriter::open(
+                    &self.daemon_log_path,
+                    args.log_keep,
+                    args.log_rotate_interval.is_some(),
+                    self.log_files.clone(),
+                )?;
+                let _ = DAEMON_LOG.set(DaemonLog::File(Mutex::new(DaemonLogFile {
+                    log,
+                    max_size: self.max_log_size.clone(),
+                    reopen_requests: LOG_REOPEN_REQUESTS.load(std::sync::atomic::Ordering::SeqCst),
+                })));
+            }
+
+            let log_options = LogOptions {
+                keep: args.log_keep,
+                timestamp_lines: args.timestamp_lines,
+                rotate_interval: args.log_rotate_interval,
+                buffer_size: args
+                    .log_buffer_kib
+                    .checked_mul(1024)
+                    .context("the log buffer size is too large")?,
+                overflow: args.log_overflow,
+                max_lines_per_sec: args.log_max_lines_per_sec,
+                flush_interval: args.log_flush_interval,
+            };
+            let (read_pipe, write_pipe) = self.log_pipe(log_options)?;
+            let syslog = args.syslog(syslog::Severity::Info);
+            self.spawn_log_thread(read_pipe, &self.log_path, log_options, syslog, stdout_tee)?;
+            dup2_stdout(&write_pipe).context("failed to redirect stdout")?;
+
+            if args.pty {
+                let pty = open_pty()?;
+                let writer = write_pipe.try_clone().context("failed to clone pipe")?;
+                self.spawn_pty_relay_thread(File::from(pty.master), writer)?;
+                child_config.pty = Some(pty.slave);
+            }
+
+            if args.separate_stderr {
+                let (read_pipe, write_pipe) = self.log_pipe(log_options)?;
+                let syslog = args.syslog(syslog::Severity::Error);
+                self.spawn_log_thread(
+                    read_pipe,
+                    &self.stderr_log_path,
+                    log_options,
+                    syslog,
+                    stderr_tee,
+                )?;
+                dup2_stderr(&write_pipe).context("failed to redirect stderr")?;
+            } else {
+                dup2_stderr(&write_pipe).context("failed to redirect stderr")?;
+            }
+        }
+
+        let health_check = args.health_check(&child_config);
+        let overlap_check = health_check.clone().filter(|_| args.overlap_restart);
+        // The child started by --overlap-restart, until the supervision loop adopts it
+        let takeover: Mutex<Option<Box<dyn ChildProcess>>> = Mutex::new(None);
+        let health_query = health_check
+            .clone()
+            .map(|check| (check, args.health_interval.min(HEALTH_QUERY_TIMEOUT)));
+        let control = self.spawn_control_thread(
+            args.restart_on,
+            stop_signal,
+            health_query,
+            args.socket_mode,
+        );
+        if let Err(err) = control {
+            log!(LogLevel::Error, "Failed to set up control socket: {:#}", err);
+        }
+
+        // Without a health check the child counts as ready as soon as it is spawned
+        let notify_ready = args.sd_notify && health_check.is_none();
+
+        if args.sd_notify {
+            self.spawn_watchdog_thread();
+        }
+
+        if let Some(listener) = metrics_listener {
+            self.spawn_metrics_thread(listener);
+        }
+
+        if args.exit_with_parent {
+            self.spawn_parent_watch_thread(parent, args.foreground);
+        }
+
+        if let Some(check) = health_check {
+            // Until a new child touches the heartbeat file, the file still holds the time
+            // the previous one touched it and fails the checks, so the new child gets a
+            // whole timeout to touch it first
+            let start_delay = match &check {
+                HealthCheck::Heartbeat(heartbeat) => args.health_start_delay.max(heartbeat.timeout),
+                _ => args.health_start_delay,
+            };
+            let options = HealthOptions {
+                interval: args.health_interval,
+                failures: args.health_failures,
+                start_delay,
+                ready_timeout: args.ready_timeout,
+            };
+            self.spawn_health_thread(check, options, stop_signal, args.sd_notify);
+        }
+
+        if !watch_paths.is_empty() {
+            self.spawn_watch_thread(watch_paths, args.watch_debounce, stop_signal);
+        }
+
+        let restarts = self.load_stats().restarts;
+        self.update_state(|snapshot| {
+            snapshot.daemon_pid = Some(daemon_pid);
+            snapshot.restarts = restarts;
+        });
+
+        // The stop signal ends the delay like any other sleep of the daemon
+        if let Some(delay) = args.start_delay {
+            log!(
+                LogLevel::Info,
+                "Waiting {} before starting the child",
+                format_duration(delay)
+            );
+            self.sleep_while_running(delay);
+        }
+
+        let exit_code = thread::scope(|scope| {
+            for (name, command) in &args.units {
+                let (daemon, args, child_config, run_info) =
+                    (&*self, &args, &child_config, &run_info);
+                scope.spawn(move || {
+                    daemon.supervise_unit(name, command, args, child_config, daemon_pid, run_info)
+                });
+            }
+
+            if let Some(check) = &overlap_check {
+                let (daemon, args, child_config, takeover) =
+                    (&*self, &args, &child_config, &takeover);
+                scope.spawn(move || {
+                    daemon.overlap_restarts(args, child_config, listen_fds, check, takeover)
+                });
+            }
+
+            let run = ChildRun {
+                daemon_pid,
+                run_info: &run_info,
+                listen_fds,
+                max_backoff,
+                grace_deadline,
+                cnt,
+                takeover: &takeover,
+                notify_ready,
+            };
+            let exit_code = self.supervise_child(&args, &child_config, run);
+
+            // The units are only told to stop here, their threads are joined when the
+            // scope ends
+            for pid in self.unit_pids.lock().unwrap().values() {
+                _ = self.spawner.kill_group(*pid, stop_signal);
+            }
+            exit_code
+        });
+
+        Ok(exit_code)
+    }
+
+    /// Runs the child and restarts it as the restart policy says, until the guard shuts
+    /// down or gives up. Returns the exit code of the daemon
+    fn supervise_child(
+        &self,
+        args: &StartArgs,
+        child_config: &ChildConfig,
+        run: ChildRun<'_>,
+    ) -> ExitCode {
+        let ChildRun {
+            daemon_pid,
+            run_info,
+            listen_fds,
+            max_backoff,
+            grace_deadline,
+            cnt,
+            takeover,
+            mut notify_ready,
+        } = run;
+        // The delay before the next restart, doubled after each consecutive fast failure
+        // when backoff is enabled
+        let mut next_interval = self.restart_interval();
+        let mut consecutive_failures = 0u64;
+        // When the restarts within --restart-window happened, oldest first
+        let mut recent_restarts: VecDeque<Instant> = VecDeque::new();
+        let mut stats = self.load_stats();
+        // The exit code the child had before the current spawn, if this is a restart
+        let mut restarted_after: Option<Option<i32>> = None;
+        // Counts the spawns of this guard, telling apart the incarnations of the child in
+        // the logs. The restart count in the stats is kept across guards instead
+        let mut generation = 0u64;
+        let mut exit_code = ExitCode::SUCCESS;
+
+        loop {
+            // The stop signal arrived while no child was running, e.g. between restarts
+            if !self.running.load(std::sync::atomic::Ordering::SeqCst) {
+                self.shutdown("stopped");
+                break;
+            }
+
+            let adopted = takeover.lock().unwrap().take();
+            if adopted.is_none()
+                && let Some(hook) = &args.pre_start
+                && let Err(err) = run_hook("pre-start", hook, args, child_config)
+            {
+                log!(LogLevel::Error, "Hook failed: {:#}", err);
+                if args.hooks_fatal {
+                    log!(LogLevel::Error, "Hooks are fatal, shutting down");
+                    // Reported as a failed start while still in the grace period
+                    cnt.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                    self.shutdown("stopped");
+                    break;
+                }
+            }
+
+            generation += 1;
+            self.child_spawned_at
+                .lock()
+                .unwrap()
+                .replace(SystemTime::now());
+            // A child started by --overlap-restart is already running. A failed stage
+            // counts as a failed spawn, the restart policy decides whether to retry
+            let spawned = match adopted {
+                Some(child) => Ok(child),
+                None => match run_stages(&args.stages, args, child_config) {
+                    Ok(()) => {
+                        let placeholders = Placeholders {
+                            restart_count: stats.restarts,
+                            instance: generation,
+                        };
+                        let spawner = self.spawner.as_ref();
+                        spawn_child(spawner, args, child_config, listen_fds, &placeholders)
+                    }
+                    Err(err) => Err(std::io::Error::other(format!("{:#}", err))),
+                },
+            };
+
+            let started_at = self.clock.now();
+            // A child that can't be spawned is handled like one that crashed right away
+            let (child_pid, status, usage) = match spawned {
+                Ok(mut child) => {
+                    let child_pid = Pid::from_raw(child.id() as i32);
+                    self.join_cgroup(child_pid);
+                    log!(
+                        LogLevel::Debug,
+                        "Spawned child process {} (generation {}): {}",
+                        child_pid,
+                        generation,
+                        quote_command(&args.command)
+                    );
+                    self.child_pid.lock().unwrap().replace(child_pid);
+                    // The stop signal may have arrived before the child was recorded
+                    if !self.running.load(std::sync::atomic::Ordering::SeqCst) {
+                        _ = self.spawner.kill_group(child_pid, args.stop_signal);
+                    }
+                    record_event(
+                        "spawn",
+                        serde_json::json!({
+                            "pid": child_pid.as_raw(),
+                            "generation": generation,
+                        }),
+                    );
+                    if let Err(err) = self.save_pids(daemon_pid, run_info) {
+                        log!(LogLevel::Error, "Failed to save PIDs: {:#}", err);
+                    }
+                    self.update_state(|snapshot| {
+                        snapshot.state = "running";
+                        snapshot.generation = generation;
+                        snapshot.child_pid = Some(child_pid);
+                        snapshot.child_started_at = Some(Utc::now().to_rfc3339());
+                    });
+
+                    if let Some(signal) = args.post_spawn_signal {
+                        self.spawn_post_spawn_signal_thread(
+                            child_pid,
+                            signal,
+                            args.post_spawn_delay,
+                        );
+                    }
+
+                    if let Some(url) = &args.notify_webhook
+                        && let Some(last_exit_code) = restarted_after
+                    {
+                        send_webhook(
+                            url,
+                            "restart",
+                            Some(child_pid),
+                            last_exit_code,
+                            stats.restarts,
+                        );
+                    }
+
+                    if notify_ready {
+                        notify_ready = false;
+                        if let Err(err) = sd_notify("READY=1") {
+                            log!(LogLevel::Error, "Failed to notify systemd: {:#}", err);
+                        }
+                    }
+
+                    let (status, usage) = wait_child(child.as_mut());
+                    let used =
+                        usage.map_or_else(String::new, |usage| format!(", it used {}", usage));
+                    log!(
+                        exit_level(status),
+                        "Child process {} (generation {}, {} restarts) exited with status {}{}",
+                        child_pid,
+                        generation,
+                        stats.restarts,
+                        status,
+                        used
+                    );
+                    record_event(
+                        "exit",
+                        serde_json::json!({
+                            "pid": child_pid.as_raw(),
+                            "generation": generation,
+                            "exit_code": status.code(),
+                            "status": describe_exit(status),
+                        }),
+                    );
+                    (Some(child_pid), status, usage)
+                }
+                Err(err) => {
+                    log!(
+                        LogLevel::Error,
+                        "Failed to spawn child process (generation {}): {}",
+                        generation,
+                        err
+                    );
+                    record_event(
+                        "spawn_failed",
+                        serde_json::json!({ "error": err.to_string() }),
+                    );
+                    (None, failed_status(), None)
+                }
+            };
+            let uptime = self.clock.now().saturating_duration_since(started_at);
+
+            // Replaced by a new child that is already running, which takes over right
+            // away whatever the restart policy says
+            if takeover.lock().unwrap().is_some()
+                && self.running.load(std::sync::atomic::Ordering::SeqCst)
+            {
+                stats.record_exit(status, usage);
+                stats.record_restart();
+                record_event("restart", serde_json::json!({ "restarts": stats.restarts }));
+                restarted_after = Some(status.code());
+                if let Err(err) = self.save_stats(&stats) {
+                    log!(LogLevel::Error, "Failed to save stats: {}", err);
+                }
+                self.update_state(|snapshot| snapshot.restarts = stats.restarts);
+                continue;
+            }
+
+            // A one-shot job may well be done within the grace period, and a child stopped
+            // by the stop signal didn't fail to start either
+            let done = status.success() && (args.once || args.stop_on_success);
+            let stopping = !self.running.load(std::sync::atomic::Ordering::SeqCst);
+            if !done && !stopping {
+                cnt.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+            // A child that never became ready failed to start, however it exited
+            let ready_failed = self
+                .ready_failed
+                .swap(false, std::sync::atomic::Ordering::SeqCst);
+
+            // With --once the exit code of the child tells how it went instead
+            if self.clock.now() < grace_deadline && !args.once && !done && !stopping {
+                log!(LogLevel::Error, "Child process exited in grace period, startup failed");
+                // Keep the output explaining the failure
+                self.running
+                    .store(false, std::sync::atomic::Ordering::SeqCst);
+                self.capturing
+                    .store(false, std::sync::atomic::Ordering::SeqCst);
+                record_event("shutdown", serde_json::json!({ "state": "failed" }));
+                join_log_threads(&self.log_threads);
+                std::process::exit(1);
+            }
+
+            self.update_state(|snapshot| {
+                snapshot.state = "restarting";
+                snapshot.last_exit_code = status.code();
+                snapshot.last_exit_status = Some(describe_exit(status));
+            });
+
+            // Saved right away, the daemon may give up or stop instead of restarting
+            stats.record_exit(status, usage);
+            if let Err(err) = self.save_stats(&stats) {
+                log!(LogLevel::Error, "Failed to save stats: {}", err);
+            }
+
+            if let Some(url) = &args.notify_webhook
+                && !status.success()
+            {
+                send_webhook(url, "exit", child_pid, status.code(), stats.restarts);
+            }
+
+            if let Some(hook) = &args.post_stop
+                && child_pid.is_some()
+                && let Err(err) = run_hook("post-stop", hook, args, child_config)
+            {
+                log!(LogLevel::Error, "Hook failed: {:#}", err);
+                if args.hooks_fatal {
+                    log!(LogLevel::Error, "Hooks are fatal, shutting down");
+                    self.shutdown("stopped");
+                    break;
+                }
+            }
+
+            // Only shut down now that the child exited, so that its last output is drained
+            if stopping {
+                self.shutdown("stopped");
+                break;
+            }
+
+            if args.once {
+                log!(LogLevel::Info, "Child process ran once, shutting down");
+                exit_code = stats.daemon_exit_code();
+                self.shutdown("stopped");
+                break;
+            }
+
+            if args.stop_on_success && status.success() {
+                log!(LogLevel::Info, "Child process exited successfully, shutting down");
+                self.shutdown("stopped");
+                break;
+            }
+
+            let restart = if ready_failed {
+                args.restart_on != RestartOn::Never
+            } else {
+                should_restart(args.restart_on, &args.restart_exit_codes, status)
+            };
+            if !restart {
+                log!(LogLevel::Info, "Restart policy does not allow restarting, shutting down");
+                exit_code = stats.daemon_exit_code();
+                self.shutdown("stopped");
+                break;
+            }
+
+            match args.min_uptime {
+                _ if ready_failed => consecutive_failures += 1,
+                // With a minimum uptime, only runs that end too soon count as failures
+                // and a run that lasted long enough recovers however it ended
+                Some(min_uptime) if uptime < min_uptime => {
+                    consecutive_failures += 1;
+                    log!(
+                        LogLevel::Warn,
+                        "Child process exited after {:.1} seconds, less than the minimum \
+                         uptime of {} ({} in a row)",
+                        uptime.as_secs_f64(),
+                        format_duration(min_uptime),
+                        consecutive_failures
+                    );
+                }
+                Some(_) => consecutive_failures = 0,
+                None if status.success() => consecutive_failures = 0,
+                None => consecutive_failures += 1,
+            }
+
+            // The first failure is the initial run, every further one is a failed restart
+            if args.max_restarts > 0 && consecutive_failures > args.max_restarts {
+                log!(LogLevel::Error, "Giving up after {} restarts", args.max_restarts);
+                if let Err(err) = self.save_gave_up(args.max_restarts) {
+                    log!(LogLevel::Error, "Failed to save state: {}", err);
+                }
+                exit_code = stats.daemon_exit_code();
+                self.shutdown("gave_up");
+                break;
+            }
+
+            if args.restart_limit > 0 {
+                let now = self.clock.now();
+                while recent_restarts
+                    .front()
+                    .is_some_and(|&at| now.duration_since(at) > args.restart_window)
+                {
+                    recent_restarts.pop_front();
+                }
+                recent_restarts.push_back(now);
+                if recent_restarts.len() > args.restart_limit as usize {
+                    log!(
+                        LogLevel::Error,
+                        "Giving up after {} restarts within {}",
+                        args.restart_limit,
+                        format_duration(args.restart_window)
+                    );
+                    if let Err(err) = self.save_gave_up(args.restart_limit.into()) {
+                        log!(LogLevel::Error, "Failed to save state: {}", err);
+                    }
+                    exit_code = stats.daemon_exit_code();
+                    self.shutdown("gave_up");
+                    break;
+                }
+            }
+
+            // A clean exit, e.g. of a job restarted by --restart-on always, resets the backoff
+            // like a stable run does
+            let failed = ready_failed || !status.success();
+            let restart_interval = self.restart_interval();
+            let interval = match max_backoff {
+                Some(max_backoff) if failed && uptime < BACKOFF_RESET_UPTIME => {
+                    let interval = next_interval;
+                    next_interval = (next_interval * 2).min(max_backoff);
+                    interval
+                }
+                _ => {
+                    next_interval = restart_interval;
+                    restart_interval
+                }
+            };
+
+            if self.running.load(std::sync::atomic::Ordering::SeqCst) {
+                // Drawn anew for every restart
+                let jitter = args.restart_jitter.map(random_duration);
+                let jittered = jitter.map_or_else(String::new, |jitter| {
+                    format!(" ({} of jitter)", format_duration(jitter))
+                });
+                let interval = interval + jitter.unwrap_or_default();
+                log!(
+                    LogLevel::Warn,
+                    "Restarting child process in {}{} as generation {} (restart {})...",
+                    format_duration(interval),
+                    jittered,
+                    generation + 1,
+                    stats.restarts + 1
+                );
+                self.sleep_while_running(interval);
+
+                stats.record_restart();
+                record_event("restart", serde_json::json!({ "restarts": stats.restarts }));
+                restarted_after = Some(status.code());
+                if let Err(err) = self.save_stats(&stats) {
+                    log!(LogLevel::Error, "Failed to save stats: {}", err);
+                }
+                self.update_state(|snapshot| snapshot.restarts = stats.restarts);
+            }
+        }
+        exit_code
+    }
+
+    /// Replaces the child on each reload with --overlap-restart: starts a new one next to it
+    /// and hands it to the supervision loop once it passes the health check, then stops the
+    /// old one. A new child that doesn't become healthy is stopped and the old one kept.
+    /// The children are spawned from this thread, which lives as long as the guard, as they
+    /// are sent the stop signal once the thread that spawned them exits
+    fn overlap_restarts(
+        &self,
+        args: &StartArgs,
+        child_config: &ChildConfig,
+        listen_fds: usize,
+        check: &HealthCheck,
+        takeover: &Mutex<Option<Box<dyn ChildProcess>>>,
+    ) {
+        // The same time the health thread gives a new child before restarting it
+        let timeout = args
+            .ready_timeout
+            .unwrap_or(args.health_start_delay + args.health_interval * args.health_failures);
+
+        while self.running.load(std::sync::atomic::Ordering::SeqCst) {
+            if !self
+                .overlap_requested
+                .swap(false, std::sync::atomic::Ordering::SeqCst)
+            {
+                thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+            let Some(old_pid) = *self.child_pid.lock().unwrap() else {
+                log!(LogLevel::Warn, "No child process to replace is running");
+                continue;
+            };
+
+            // Taking over counts as a restart and a new generation
+            let placeholders = {
+                let snapshot = self.state_snapshot.lock().unwrap();
+                Placeholders {
+                    restart_count: snapshot.restarts + 1,
+                    instance: snapshot.generation + 1,
+                }
+            };
+            let spawned = spawn_child(
+                self.spawner.as_ref(),
+                args,
+                child_config,
+                listen_fds,
+                &placeholders,
+            );
+            let mut child = match spawned {
+                Ok(child) => child,
+                Err(err) => {
+                    log!(
+                        LogLevel::Error,
+                        "Failed to spawn a new child process, keeping {}: {}",
+                        old_pid,
+                        err
+                    );
+                    continue;
+                }
+            };
+            let new_pid = Pid::from_raw(child.id() as i32);
+            self.join_cgroup(new_pid);
+            log!(
+                LogLevel::Info,
+                "Started child process {} to replace {}, waiting for it to become healthy",
+                new_pid,
+                old_pid
+            );
+
+            let deadline = Instant::now() + timeout;
+            self.sleep_while_running(args.health_start_delay.min(timeout));
+            let healthy = loop {
+                if !self.running.load(std::sync::atomic::Ordering::SeqCst) {
+                    break false;
+                }
+                if let Ok(Some(status)) = child.try_wait() {
+                    log!(
+                        LogLevel::Warn,
+                        "New child process {} exited with status {} before becoming healthy",
+                        new_pid,
+                        status
+                    );
+                    break false;
+                }
+                if check.probe(args.health_interval).is_ok() {
+                    break true;
+                }
+                if Instant::now() >= deadline {
+                    log!(
+                        LogLevel::Warn,
+                        "New child process {} failed to become healthy within {}",
+                        new_pid,
+                        format_duration(timeout)
+                    );
+                    break false;
+                }
+                self.sleep_while_running(args.health_interval);
+            };
+
+            // The old child may have been restarted in the meantime, that one stays
+            if healthy && *self.child_pid.lock().unwrap() == Some(old_pid) {
+                log!(
+                    LogLevel::Info,
+                    "New child process {} is healthy, stopping {}",
+                    new_pid,
+                    old_pid
+                );
+                record_event(
+                    "overlap_restart",
+                    serde_json::json!({
+                        "old_pid": old_pid.as_raw(),
+                        "new_pid": new_pid.as_raw(),
+                    }),
+                );
+                takeover.lock().unwrap().replace(child);
+                _ = self.spawner.kill_group(old_pid, args.stop_signal);
+            } else {
+                if healthy {
+                    log!(
+                        LogLevel::Warn,
+                        "Child process {} was restarted meanwhile, stopping {}",
+                        old_pid,
+                        new_pid
+                    );
+                } else if self.running.load(std::sync::atomic::Ordering::SeqCst) {
+                    log!(LogLevel::Warn, "Keeping child process {}", old_pid);
+                    record_event(
+                        "overlap_failed",
+                        serde_json::json!({
+                            "old_pid": old_pid.as_raw(),
+                            "new_pid": new_pid.as_raw(),
+                        }),
+                    );
+                }
+                _ = self.spawner.kill_group(new_pid, Signal::SIGKILL);
+                let _ = child.wait();
+            }
+        }
+    }
+
+    /// Runs a named unit next to the child, restarting it by the same policy and interval
+    /// until the guard shuts down or the unit is stopped with `stop --unit`. Backoff,
+    /// max restarts, health checks and stats only apply to the child
+    fn supervise_unit(
+        &self,
+        name: &str,
+        command: &[String],
+        args: &StartArgs,
+        child_config: &ChildConfig,
+        daemon_pid: Pid,
+        run_info: &RunInfo,
+    ) {
+        let stop_signal = args.stop_signal;
+        let stop_file = self.unit_stop_file(name);
+        let _ = std::fs::remove_file(&stop_file);
+
+        while self.running.load(std::sync::atomic::Ordering::SeqCst) {
+            let mut unit_command = build_command(command, args, child_config);
+            unsafe {
+                unit_command.pre_exec(move || {
+                    prctl(libc::PR_SET_PDEATHSIG, stop_signal as libc::c_int);
+                    std::io::Result::Ok(())
+                });
+            }
+            let spawned = self.spawner.spawn(&mut unit_command);
+
+            match spawned {
+                Ok(mut child) => {
+                    let pid = Pid::from_raw(child.id() as i32);
+                    self.join_cgroup(pid);
+                    self.unit_pids.lock().unwrap().insert(name.to_string(), pid);
+                    record_event(
+                        "spawn",
+                        serde_json::json!({ "unit": name, "pid": pid.as_raw() }),
+                    );
+                    if let Err(err) = self.save_pids(daemon_pid, run_info) {
+                        log!(LogLevel::Error, "Failed to save PIDs: {:#}", err);
+                    }
+                    // The guard may have shut down while the unit was being spawned
+                    if !self.running.load(std::sync::atomic::Ordering::SeqCst) {
+                        _ = self.spawner.kill_group(pid, stop_signal);
+                    }
+
+                    let (status, _) = wait_child(child.as_mut());
+                    log!(
+                        exit_level(status),
+                        "Unit {} process {} exited with status {}",
+                        name,
+                        pid,
+                        status
+                    );
+                    record_event(
+                        "exit",
+                        serde_json::json!({
+                            "unit": name,
+                            "pid": pid.as_raw(),
+                            "exit_code": status.code(),
+                            "status": describe_exit(status),
+                        }),
+                    );
+
+                    if std::fs::remove_file(&stop_file).is_ok() {
+                        log!(LogLevel::Info, "Unit {} was stopped", name);
+                        break;
+                    }
+                    if !should_restart(args.restart_on, &args.restart_exit_codes, status) {
+                        log!(
+                            LogLevel::Info,
+                            "Restart policy does not allow restarting unit {}",
+                            name
+                        );
+                        break;
+                    }
+                }
+                Err(err) => {
+                    log!(LogLevel::Error, "Failed to spawn unit {}: {}", name, err);
+                }
+            }
+
+            let interval = self.restart_interval();
+            if self.running.load(std::sync::atomic::Ordering::SeqCst) {
+                log!(
+                    LogLevel::Warn,
+                    "Restarting unit {} in {}...",
+                    name,
+                    format_duration(interval)
+                );
+                record_event("restart", serde_json::json!({ "unit": name }));
+            }
+            self.sleep_while_running(interval);
+        }
+    }
+
+    /// Runs the checks of `start` and prints what would be supervised, without forking
+    fn dry_run(
+        &mut self,
+        args: &StartArgs,
+        child_config: &ChildConfig,
+        run_info: &RunInfo,
+    ) -> Result<()> {
+        let path = child_config.path(args);
+
+        let program = resolve_program(&args.command[0], &child_config.workdir, path.as_deref())?;
+        let mut units = Vec::new();
+        for (name, command) in &args.units {
+            let unit_program = resolve_program(&command[0], &child_config.workdir, path.as_deref())
+                .with_context(|| format!("unit {}", name))?;
+            units.push((name, unit_program, command));
+        }
+
+        let status_dir = self
+            .pid_file
+            .parent()
+            .unwrap_or(Path::new("."))
+            .to_path_buf();
+        let probe = status_dir.join(".dry-run");
+        std::fs::write(&probe, "")
+            .with_context(|| format!("status dir is not writable: {}", status_dir.display()))?;
+        let _ = std::fs::remove_file(&probe);
+
+        self.try_lock(args.lock_wait)?;
+
+        println!("Dry run, nothing was started");
+        let mut argv = vec![program.display().to_string()];
+        argv.extend_from_slice(&args.command[1..]);
+        println!("Command: {}", quote_command(&argv));
+        for (name, program, command) in units {
+            let mut argv = vec![program.display().to_string()];
+            argv.extend_from_slice(&command[1..]);
+            println!("Unit {}: {}", name, quote_command(&argv));
+        }
+        for (index, stage) in args.stages.iter().enumerate() {
+            println!("Stage {}: {}", index + 1, stage);
+        }
+        println!("Working directory: {}", child_config.workdir.display());
+        println!("Status dir: {}", status_dir.display());
+        println!(
+            "Restart: {}, interval {}, max restarts {}",
+            run_info
+                .restart_on
+                .to_possible_value()
+                .expect("no skipped variants")
+                .get_name(),
+            format_duration(run_info.restart_interval),
+            run_info.max_restarts
+        );
+        if args.foreground && !args.tee {
+            println!("Output: kept on the terminal");
+        } else {
+            println!(
+                "Output: {}, rotated at {} MiB",
+                self.log_path.display(),
+                run_info.max_log_size_mib
+            );
+        }
+        Ok(())
+    }
+
+    /// Prints the paths of the files of the guard, as resolved from the status dir and the
+    /// file overrides. Nothing needs to run, the files may not exist yet
+    fn info(&self, json: bool) -> Result<()> {
+        let paths = [
+            ("status_dir", "Status dir", &self.status_dir),
+            ("pid_file", "PID file", &self.pid_file),
+            ("run_info_file", "Run info", &self.run_info_file),
+            ("lock_file", "Lock file", &self.lock_file),
+            ("log_file", "Output log", &self.log_path),
+            ("stderr_log_file", "Stderr log", &self.stderr_log_path),
+            ("daemon_log_file", "Daemon log", &self.daemon_log_path),
+            ("state_file", "State", &self.state_json_file),
+            ("events_file", "Events", &self.journal_path),
+            ("control_socket", "Control socket", &self.control_socket),
+        ];
+        if json {
+            let mut info = serde_json::Map::new();
+            for (key, _, path) in paths {
+                info.insert(key.to_string(), path.display().to_string().into());
+            }
+            println!("{}", serde_json::Value::Object(info));
+            return Ok(());
+        }
+        for (_, name, path) in paths {
+            println!("{}: {}", name, path.display());
+        }
+        Ok(())
+    }
+
+    /// Moves a freshly spawned process into the cgroup of --cgroup, if there is one, in case
+    /// it couldn't move itself before exec
+    fn join_cgroup(&self, pid: Pid) {
+        if let Some(cgroup) = &self.cgroup
+            && let Err(err) = cgroup.add(pid)
+        {
+            log!(LogLevel::Error, "Failed to join cgroup: {:#}", err);
+        }
+    }
+
+    fn restart_interval(&self) -> Duration {
+        Duration::from_millis(
+            self.restart_interval_ms
+                .load(std::sync::atomic::Ordering::SeqCst),
+        )
+    }
+
+    /// Sleeps for the duration, waking up early when the guard shuts down
+    fn sleep_while_running(&self, duration: Duration) {
+        let deadline = self.clock.now() + duration;
+        while self.clock.now() < deadline && self.running.load(std::sync::atomic::Ordering::SeqCst)
+        {
+            let remaining = deadline.saturating_duration_since(self.clock.now());
+            self.clock.sleep(remaining.min(Duration::from_millis(100)));
+        }
+    }
+
+    /// Applies the hot-reloadable settings, flags given to start take precedence over the
+    /// config file
+    fn apply_config(&self, args: &StartArgs, config: &Config) {
+        self.restart_interval_ms.store(
+            args.restart_interval(config).as_millis() as u64,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+        self.max_log_size.store(
+            args.max_log_size_mib(config) << 20,
+            std::sync::atomic::Ordering::SeqCst,
+        );
+    }
+
+    /// Re-reads the config file whenever SIGHUP arrives and applies it to the running guard
+    fn spawn_reload_thread(&self, args: &StartArgs) -> thread::JoinHandle<()> {
+        let daemon = ReloadHandle {
+            running: self.running.clone(),
+            child_pid: self.child_pid.clone(),
+            restart_interval_ms: self.restart_interval_ms.clone(),
+            max_log_size: self.max_log_size.clone(),
+            overlap_requested: self.overlap_requested.clone(),
+            spawner: self.spawner.clone(),
+        };
+        let config_path = args.config.clone();
+        let cli_restart_interval = args.restart_interval;
+        let cli_max_log_size_mib = args.max_log_size_mib;
+        let hup_restarts_child = args.hup_restarts_child;
+        let overlap_restart = args.overlap_restart;
+        let stop_signal = args.stop_signal;
+
+        thread::spawn(move || {
+            while daemon.running.load(std::sync::atomic::Ordering::SeqCst) {
+                if !RELOAD_REQUESTED.swap(false, std::sync::atomic::Ordering::SeqCst) {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+
+                let config = match &config_path {
+                    Some(path) => match Config::load(path) {
+                        Ok(config) => config,
+                        Err(err) => {
+                            log!(
+                                LogLevel::Error,
+                                "Failed to reload config, keeping current settings: {:#}",
+                                err
+                            );
+                            continue;
+                        }
+                    },
+                    None => Config::default(),
+                };
+
+                let restart_interval = cli_restart_interval
+                    .or(config.restart_interval)
+                    .unwrap_or(DEFAULT_RESTART_INTERVAL);
+                let max_log_size_mib = cli_max_log_size_mib
+                    .or(config.max_log_size_mib)
+                    .unwrap_or(DEFAULT_MAX_LOG_SIZE_MIB);
+                daemon.restart_interval_ms.store(
+                    restart_interval.as_millis() as u64,
+                    std::sync::atomic::Ordering::SeqCst,
+                );
+                daemon
+                    .max_log_size
+                    .store(max_log_size_mib << 20, std::sync::atomic::Ordering::SeqCst);
+                log!(
+                    LogLevel::Info,
+                    "Reloaded config: restart interval {}, max log size {} MiB",
+                    format_duration(restart_interval),
+                    max_log_size_mib
+                );
+                record_event("reload", serde_json::json!({}));
+
+                if overlap_restart {
+                    daemon
+                        .overlap_requested
+                        .store(true, std::sync::atomic::Ordering::SeqCst);
+                } else if hup_restarts_child && let Some(pid) = *daemon.child_pid.lock().unwrap() {
+                    log!(
+                        LogLevel::Info,
+                        "Sending {} to child process {} to restart it",
+                        stop_signal,
+                        pid
+                    );
+                    _ = daemon.spawner.kill_group(pid, stop_signal);
+                }
+            }
+        })
+    }
+
+    /// Updates the live state and mirrors it into `state.json`
+    fn update_state(&self, update: impl FnOnce(&mut StateSnapshot)) {
+        let mut snapshot = self.state_snapshot.lock().unwrap();
+        update(&mut snapshot);
+        if let Err(err) = write_state_json(&self.state_json_file, &snapshot) {
+            log!(LogLevel::Error, "Failed to write state: {:#}", err);
+        }
+    }
+
+    /// Stops the supervision loop, records the final state and flushes the log file to disk
+    fn shutdown(&self, state: &'static str) {
+        self.running
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        self.update_state(|snapshot| snapshot.state = state);
+        record_event("shutdown", serde_json::json!({ "state": state }));
+        let _ = std::fs::remove_file(&self.control_socket);
+        self.capturing
+            .store(false, std::sync::atomic::Ordering::SeqCst);
+        join_log_threads(&self.log_threads);
+        // Joined without holding the lock of the log, a failure is logged to it
+        if let Some(DaemonLog::File(log)) = DAEMON_LOG.get() {
+            let compression = log.lock().unwrap().log.compression.take();
+            join_compression(compression);
+        }
+        for f in self.log_files.lock().unwrap().iter() {
+            if let Err(err) = f.sync_all() {
+                log!(LogLevel::Error, "Failed to sync log file: {}", err);
+            }
+        }
+    }
+
+    /// Creates the pipe the child writes its output to, holding up to --log-buffer-kib
+    /// while the log thread catches up. With --log-overflow drop the log thread reads it
+    /// through a relay instead, see spawn_relay_thread
+    fn log_pipe(&self, options: LogOptions) -> Result<(PipeReader, PipeWriter)> {
+        let (reader, writer) = std::io::pipe().context("failed to create pipe")?;
+        set_pipe_size(&reader, options.buffer_size);
+        let reader = match options.overflow {
+            LogOverflow::Block => reader,
+            LogOverflow::Drop => self.spawn_relay_thread(reader, options.buffer_size)?,
+        };
+        Ok((reader, writer))
+    }
+
+    /// Keeps draining the pipe of the child into a second pipe the log thread reads, and
+    /// drops the lines that don't fit into it while the log thread falls behind, so that a
+    /// slow log never blocks the child on a full pipe
+    fn spawn_relay_thread(&self, reader: PipeReader, buffer_size: usize) -> Result<PipeReader> {
+        let (relay_reader, mut relay_writer) = std::io::pipe().context("failed to create pipe")?;
+        set_pipe_size(&relay_reader, buffer_size);
+        set_nonblocking(&relay_writer).context("failed to make the log pipe non-blocking")?;
+        let capturing = self.capturing.clone();
+        let state_snapshot = self.state_snapshot.clone();
+        let state_json_file = self.state_json_file.clone();
+
+        let handle = thread::spawn(move || {
+            let mut reader = reader;
+            let mut buf = vec![0; buffer_size];
+            let mut dropped = 0;
+            let mut state = RelayState::Writing { mid_line: false };
+            let mut last_report: Option<Instant> = None;
+            let mut report = |dropped: &mut u64, force: bool| {
+                if *dropped == 0
+                    || !force && last_report.is_some_and(|t| t.elapsed() < LOG_DROP_REPORT_INTERVAL)
+                {
+                    return;
+                }
+                log!(
+                    LogLevel::Warn,
+                    "Dropped {} bytes of output, the log can't keep up with the child",
+                    dropped
+                );
+                let snapshot = state_snapshot.lock().unwrap();
+                if let Err(err) = write_state_json(&state_json_file, &snapshot) {
+                    log!(LogLevel::Error, "Failed to write state: {:#}", err);
+                }
+                *dropped = 0;
+                last_report = Some(Instant::now());
+            };
+
+            // Like the log thread, the output left in the pipe on shutdown is still relayed
+            // until the pipe stays quiet
+            loop {
+                if !wait_readable(&reader, Some(LOG_POLL_INTERVAL)) {
+                    if !capturing.load(std::sync::atomic::Ordering::Relaxed) {
+                        break;
+                    }
+                    report(&mut dropped, false);
+                    continue;
+                }
+
+                let n = match reader.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                let Ok(lost) = relay_lines(&mut relay_writer, &buf[..n], &mut state) else {
+                    break;
+                };
+                if lost > 0 {
+                    dropped += lost;
+                    state_snapshot.lock().unwrap().dropped_log_bytes += lost;
+                    report(&mut dropped, false);
+                }
+            }
+            report(&mut dropped, true);
+        });
+        self.log_threads.lock().unwrap().push(handle);
+        Ok(relay_reader)
+    }
+
+    /// Copies what the child writes to the pseudo-terminal of --pty into the log pipe. The
+    /// daemon keeps the terminal side open, so the terminal outlives each child and is
+    /// reused by the next one
+    fn spawn_pty_relay_thread(&self, master: File, mut writer: PipeWriter) -> Result<()> {
+        let capturing = self.capturing.clone();
+        let handle = thread::spawn(move || {
+            let mut master = master;
+            // A terminal hands out no more than 4 KiB per read
+            let mut buf = [0; 4096];
+            loop {
+                if !wait_readable(&master, Some(LOG_POLL_INTERVAL)) {
+                    if !capturing.load(std::sync::atomic::Ordering::Relaxed) {
+                        break;
+                    }
+                    continue;
+                }
+                let n = match master.read(&mut buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => n,
+                };
+                if writer.write_all(&buf[..n]).is_err() {
+                    break;
+                }
+            }
+        });
+        self.log_threads.lock().unwrap().push(handle);
+        Ok(())
+    }
+
+    /// Copies the output read from the pipe into the log file or syslog, and to `tee` with
+    /// --tee, until the guard shuts down and the output left in the pipe has been written
+    fn spawn_log_thread(
+        &self,
+        reader: impl Read + AsRawFd + Send + 'static,
+        log_path: &Path,
+        options: LogOptions,
+        syslog: Option<syslog::Syslog>,
+        tee: Option<File>,
+    ) -> Result<()> {
+        let capturing = self.capturing.clone();
+        let max_log_size = self.max_log_size.clone();
+        let attached = self.attached.clone();
+        let state_snapshot = self.state_snapshot.clone();
+        let state_json_file = self.state_json_file.clone();
+        let log_name = log_path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let mut log = LogWriter::open(
+            log_path,
+            options.keep,
+            options.rotate_interval.is_some(),
+            self.log_files.clone(),
+        )?;
+
+        let handle = thread::spawn(move || {
+            let mut reader = reader;
+            let mut syslog = syslog;
+            let mut tee = tee;
+            let mut buf = vec![0; options.buffer_size];
+            // Holds an incomplete line until its newline arrives, so a line split across
+            // reads is only timestamped once, or until it waited for --log-flush-interval
+            let mut pending_line = Vec::new();
+            let mut pending_since: Option<Instant> = None;
+            let mut next_rotation = options.rotate_interval.map(next_rotation_time);
+            let mut reopen_requests = LOG_REOPEN_REQUESTS.load(std::sync::atomic::Ordering::SeqCst);
+            let mut limiter = (options.max_lines_per_sec > 0)
+                .then(|| LineRateLimiter::new(options.max_lines_per_sec));
+            // Notes the lines left out in place of them, in the log and in the live state
+            let report_suppressed = |log: &mut LogWriter,
+                                     syslog: &mut Option<syslog::Syslog>,
+                                     limiter: &mut Option<LineRateLimiter>,
+                                     force: bool| {
+                let Some(limiter) = limiter else {
+                    return;
+                };
+                let Some(suppressed) = limiter.end_second(force) else {
+                    return;
+                };
+                let msg = format!(
+                    "[{}] Suppressed {} lines over the limit of {} per second\n",
+                    Utc::now().to_rfc3339(),
+                    suppressed,
+                    limiter.max_per_sec
+                );
+                write_line(log, syslog, false, msg.as_bytes());
+                state_snapshot.lock().unwrap().suppressed_log_lines += suppressed;
+            };
+            // Rotations are counted in the live state, to tell how often the log fills up
+            let rotate = |log: &mut LogWriter, reason: &str| {
+                log.rotate(reason);
+                let mut snapshot = state_snapshot.lock().unwrap();
+                snapshot.log_rotations += 1;
+                snapshot.log_rotation_history.push_back((
+                    Utc::now().to_rfc3339(),
+                    log_name.clone(),
+                    reason.to_string(),
+                ));
+                if snapshot.log_rotation_history.len() > LOG_ROTATION_HISTORY {
+                    snapshot.log_rotation_history.pop_front();
+                }
+                if let Err(err) = write_state_json(&state_json_file, &snapshot) {
+                    log!(LogLevel::Error, "Failed to write state: {:#}", err);
+                }
+            };
+            let mut write_output = |log: &mut LogWriter,
+                                    syslog: &mut Option<syslog::Syslog>,
+                                    limiter: &mut Option<LineRateLimiter>,
+                                    pending_line: &mut Vec<u8>,
+                                    data: &[u8]| {
+                write_attached(&attached, data);
+                if let Some(terminal) = &mut tee {
+                    let _ = terminal.write_all(data);
+                }
+                if log.size > max_log_size.load(std::sync::atomic::Ordering::Relaxed) {
+                    rotate(log, "Log size exceeded");
+                }
+
+                // Timestamps, syslog records and the rate limit all need whole lines
+                if options.timestamp_lines || syslog.is_some() || limiter.is_some() {
+                    pending_line.extend_from_slice(data);
+                    while let Some(pos) = pending_line.iter().position(|&b| b == b'\n') {
+                        let line: Vec<u8> = pending_line.drain(..=pos).collect();
+                        report_suppressed(log, syslog, limiter, false);
+                        if limiter.as_mut().is_some_and(|limiter| !limiter.allow()) {
+                            continue;
+                        }
+                        write_line(log, syslog, options.timestamp_lines, &line);
+                    }
+                } else {
+                    log.write(data);
+                }
+            };
+
+            while capturing.load(std::sync::atomic::Ordering::Relaxed) {
+                // Scheduled rotations are due even while the child is quiet, so only wait
+                // for output until the next one
+                let until_rotation = next_rotation.map(|next_rotation| {
+                    let now = Utc::now().timestamp().max(0) as u64;
+                    Duration::from_secs(next_rotation.saturating_sub(now))
+                });
+                let until_flush = pending_since.map(|since| {
+                    options
+                        .flush_interval
+                        .saturating_sub(since.elapsed())
+                        .max(Duration::from_millis(1))
+                });
+                let timeout = [until_rotation, until_flush]
+                    .into_iter()
+                    .flatten()
+                    .fold(LOG_POLL_INTERVAL, Duration::min);
+                let readable = wait_readable(&reader, Some(timeout));
+
+                if let Some(rotation) = next_rotation
+                    && Utc::now().timestamp().max(0) as u64 >= rotation
+                {
+                    rotate(&mut log, "Log rotation interval elapsed");
+                    next_rotation = options.rotate_interval.map(next_rotation_time);
+                }
+                let requests = LOG_REOPEN_REQUESTS.load(std::sync::atomic::Ordering::SeqCst);
+                if requests != reopen_requests {
+                    reopen_requests = requests;
+                    log.reopen();
+                }
+                // The lines suppressed before the child went quiet are reported all the same
+                report_suppressed(&mut log, &mut syslog, &mut limiter, false);
+                // A prompt or progress output without a newline shows up all the same, ended
+                // by one. What follows it becomes a line of its own
+                if pending_since.is_some_and(|since| since.elapsed() >= options.flush_interval) {
+                    pending_since = None;
+                    pending_line.push(b'\n');
+                    let line = std::mem::take(&mut pending_line);
+                    if limiter.as_mut().is_none_or(LineRateLimiter::allow) {
+                        write_line(&mut log, &mut syslog, options.timestamp_lines, &line);
+                    }
+                }
+                if !readable {
+                    continue;
+                }
+
+                match reader.read(&mut buf) {
+                    Ok(n) if n > 0 => {
+                        write_output(
+                            &mut log,
+                            &mut syslog,
+                            &mut limiter,
+                            &mut pending_line,
+                            &buf[..n],
+                        );
+                        if pending_line.is_empty() {
+                            pending_since = None;
+                        } else if pending_since.is_none() {
+                            pending_since = Some(Instant::now());
+                        }
+                    }
+                    Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                        break;
+                    }
+                    Err(err) => {
+                        log!(LogLevel::Error, "Failed to read from pipe: {}", err);
+                        break;
+                    }
+                    Ok(_) => {
+                        // EOF
+                        break;
+                    }
+                }
+            }
+
+            // The child's last output, e.g. what it printed when it crashed or on its way
+            // out after the stop signal, is still in the pipe or about to arrive
+            while wait_readable(&reader, Some(LOG_POLL_INTERVAL)) {
+                match reader.read(&mut buf) {
+                    Ok(n) if n > 0 => write_output(
+                        &mut log,
+                        &mut syslog,
+                        &mut limiter,
+                        &mut pending_line,
+                        &buf[..n],
+                    ),
+                    _ => break,
+                }
+            }
+
+            if !pending_line.is_empty() && limiter.as_mut().is_none_or(LineRateLimiter::allow) {
+                write_line(
+                    &mut log,
+                    &mut syslog,
+                    options.timestamp_lines,
+                    &pending_line,
+                );
+            }
+            report_suppressed(&mut log, &mut syslog, &mut limiter, true);
+
+            log.finish();
+        });
+        self.log_threads.lock().unwrap().push(handle);
+        Ok(())
+    }
+
+    /// Sends --post-spawn-signal to the child once it has been up for `delay`, unless it
+    /// exited in the meantime
+    fn spawn_post_spawn_signal_thread(
+        &self,
+        pid: Pid,
+        signal: Signal,
+        delay: Duration,
+    ) -> thread::JoinHandle<()> {
+        let running = self.running.clone();
+        let state_snapshot = self.state_snapshot.clone();
+
+        thread::spawn(move || {
+            thread::sleep(delay);
+            if !running.load(std::sync::atomic::Ordering::SeqCst) {
+                return;
+            }
+
+            // The supervision loop marks the child as restarting once it has reaped it, after
+            // which its PID may belong to another process
+            let snapshot = state_snapshot.lock().unwrap();
+            if snapshot.state != "running" || snapshot.child_pid != Some(pid) {
+                return;
+            }
+            match kill(pid, signal) {
+                Ok(()) => log!(LogLevel::Info, "Sent {} to child process {}", signal, pid),
+                Err(err) => log!(
+                    LogLevel::Error,
+                    "Failed to send {} to child process {}: {}",
+                    signal,
+                    pid,
+                    err
+                ),
+            }
+        })
+    }
+
+    /// Scans the watched paths and sends the child the stop signal once they changed and
+    /// then stayed unchanged for `debounce`, so that the supervision loop restarts it
+    fn spawn_watch_thread(
+        &self,
+        paths: Vec<PathBuf>,
+        debounce: Duration,
+        stop_signal: Signal,
+    ) -> thread::JoinHandle<()> {
+        let running = self.running.clone();
+        let child_pid = self.child_pid.clone();
+        let spawner = self.spawner.clone();
+
+        thread::spawn(move || {
+            let mut snapshot = watch::Snapshot::scan(&paths);
+            let mut changed_at: Option<Instant> = None;
+
+            while running.load(std::sync::atomic::Ordering::SeqCst) {
+                thread::sleep(WATCH_POLL_INTERVAL);
+
+                let current = watch::Snapshot::scan(&paths);
+                if current != snapshot {
+                    snapshot = current;
+                    changed_at = Some(Instant::now());
+                    continue;
+                }
+
+                if changed_at.is_some_and(|changed_at| changed_at.elapsed() >= debounce) {
+                    changed_at = None;
+                    if let Some(pid) = *child_pid.lock().unwrap() {
+                        log!(
+                            LogLevel::Info,
+                            "Watched files changed, sending {} to child process {} to \
+                             restart it",
+                            stop_signal,
+                            pid
+                        );
+                        _ = spawner.kill_group(pid, stop_signal);
+                    }
+                }
+            }
+        })
+    }
+
+    /// Probes the child on an interval and sends it the stop signal after too many
+    /// consecutive failures, so that the supervision loop restarts it
+    fn spawn_health_thread(
+        &self,
+        check: HealthCheck,
+        options: HealthOptions,
+        stop_signal: Signal,
+        notify_ready: bool,
+    ) -> thread::JoinHandle<()> {
+        let running = self.running.clone();
+        let child_pid = self.child_pid.clone();
+        let child_spawned_at = self.child_spawned_at.clone();
+        let spawner = self.spawner.clone();
+        let ready_failed = self.ready_failed.clone();
+
+        thread::spawn(move || {
+            let mut check = check;
+            // Readiness is reported to systemd once the first check passes
+            let mut notify_ready = notify_ready;
+            // The child being checked, when it was first seen, whether it was signaled and
+            // whether it passed a check yet
+            let mut watched: Option<(Pid, Instant, bool, bool)> = None;
+            let mut failures = 0;
+
+            while running.load(std::sync::atomic::Ordering::SeqCst) {
+                let Some(pid) = *child_pid.lock().unwrap() else {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                };
+
+                if watched.is_none_or(|(watched_pid, _, _, _)| watched_pid != pid) {
+                    watched = Some((pid, Instant::now(), false, false));
+                    failures = 0;
+                    // A file still fresh from the previous child doesn't count for this one
+                    if let HealthCheck::Heartbeat(heartbeat) = &mut check {
+                        heartbeat.since = *child_spawned_at.lock().unwrap();
+                    }
+                }
+                let (_, seen_at, signaled, ready) = watched.unwrap();
+
+                if !signaled
+                    && !ready
+                    && let Some(timeout) = options.ready_timeout
+                    && seen_at.elapsed() >= timeout
+                {
+                    log!(
+                        LogLevel::Warn,
+                        "Child process {} failed to become ready within {}, sending {} to \
+                         restart it",
+                        pid,
+                        format_duration(timeout),
+                        stop_signal
+                    );
+                    record_event("ready_timeout", serde_json::json!({ "pid": pid.as_raw() }));
+                    ready_failed.store(true, std::sync::atomic::Ordering::SeqCst);
+                    _ = spawner.kill_group(pid, stop_signal);
+                    watched = Some((pid, seen_at, true, false));
+                    continue;
+                }
+
+                // Wait for the respawned child instead of signaling this one again
+                if signaled || seen_at.elapsed() < options.start_delay {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+
+                match check.probe(options.interval) {
+                    Ok(()) => {
+                        failures = 0;
+                        watched = Some((pid, seen_at, false, true));
+                        if notify_ready {
+                            notify_ready = false;
+                            if let Err(err) = sd_notify("READY=1") {
+                                log!(LogLevel::Error, "Failed to notify systemd: {:#}", err);
+                            }
+                        }
+                    }
+                    Err(err) => {
+                        failures += 1;
+                        log!(
+                            LogLevel::Warn,
+                            "Health check {} failed ({}/{}): {:#}",
+                            check,
+                            failures,
+                            options.failures,
+                            err
+                        );
+                        if failures >= options.failures {
+                            log!(
+                                LogLevel::Warn,
+                                "Child process {} is unhealthy, sending {} to restart it",
+                                pid,
+                                stop_signal
+                            );
+                            _ = spawner.kill_group(pid, stop_signal);
+                            watched = Some((pid, seen_at, true, ready));
+                            continue;
+                        }
+                    }
+                }
+
+                let next_probe = Instant::now() + options.interval;
+                while Instant::now() < next_probe
+                    && running.load(std::sync::atomic::Ordering::SeqCst)
+                {
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        })
+    }
+
+    /// Serves the live state in the Prometheus text format until the guard shuts down
+    fn spawn_metrics_thread(&self, listener: std::net::TcpListener) -> thread::JoinHandle<()> {
+        let running = self.running.clone();
+        let state_snapshot = self.state_snapshot.clone();
+
+        thread::spawn(move || {
+            metrics::serve(listener, running, || {
+                let snapshot = state_snapshot.lock().unwrap();
+                let child_up = snapshot.state == "running";
+                metrics::Metrics {
+                    restarts: snapshot.restarts,
+                    child_up,
+                    last_exit_code: snapshot.last_exit_code,
+                    log_dropped_bytes: snapshot.dropped_log_bytes,
+                    log_suppressed_lines: snapshot.suppressed_log_lines,
+                    child_uptime: snapshot
+                        .child_pid
+                        .filter(|_| child_up)
+                        .and_then(process_uptime)
+                        .unwrap_or_default(),
+                }
+            })
+        })
+    }
+
+    /// Shuts the guard down the same way as on SIGTERM once the process that ran guarderd
+    /// exits, so that the guard isn't left behind orphaned
+    fn spawn_parent_watch_thread(
+        &self,
+        parent: TrackedPid,
+        foreground: bool,
+    ) -> thread::JoinHandle<()> {
+        let running = self.running.clone();
+
+        thread::spawn(move || {
+            while running.load(std::sync::atomic::Ordering::SeqCst) {
+                // In the foreground the daemon is reparented right away, which also covers
+                // a parent left as a zombie
+                let reparented = foreground && unistd::getppid() != parent.pid;
+                if reparented || !parent.is_running() {
+                    log!(LogLevel::Info, "Parent process {} exited, shutting down", parent);
+                    _ = kill(unistd::getpid(), Signal::SIGTERM);
+                    break;
+                }
+                thread::sleep(PARENT_POLL_INTERVAL);
+            }
+        })
+    }
+
+    /// Pings the systemd watchdog at half its interval when WATCHDOG_USEC asks for it
+    fn spawn_watchdog_thread(&self) -> Option<thread::JoinHandle<()>> {
+        let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+        // The watchdog may be meant for another process, e.g. when not run in the foreground
+        if let Some(pid) = std::env::var("WATCHDOG_PID").ok()
+            && pid.parse::<i32>().ok() != Some(unistd::getpid().as_raw())
+        {
+            return None;
+        }
+
+        let running = self.running.clone();
+        let interval = Duration::from_micros(usec) / 2;
+        Some(thread::spawn(move || {
+            while running.load(std::sync::atomic::Ordering::SeqCst) {
+                if let Err(err) = sd_notify("WATCHDOG=1") {
+                    log!(LogLevel::Error, "Failed to ping systemd watchdog: {:#}", err);
+                }
+                thread::sleep(interval);
+            }
+        }))
+    }
+
+    /// Answers commands on the control socket, see the control module for the protocol.
+    /// `restart-child` defaults to `stop_signal`, `health` probes with `health_query`, the
+    /// health check and the timeout of a probe
+    fn spawn_control_thread(
+        &self,
+        restart_on: RestartOn,
+        stop_signal: Signal,
+        health_query: Option<(HealthCheck, Duration)>,
+        socket_mode: Option<libc::mode_t>,
+    ) -> Result<thread::JoinHandle<()>> {
+        let listener = control::listen(&self.control_socket)?;
+        // Who may connect is governed by the permissions of the socket
+        if let Some(mode) = socket_mode {
+            std::fs::set_permissions(&self.control_socket, std::fs::Permissions::from_mode(mode))
+                .context("failed to set the permissions of the control socket")?;
+        }
+        let child_pid = self.child_pid.clone();
+        let spawner = self.spawner.clone();
+        let unit_pids = self.unit_pids.clone();
+        let state_snapshot = self.state_snapshot.clone();
+        let stop_signal_file = self.stop_signal_file.clone();
+        let log_threads = self.log_threads.clone();
+        let attached = self.attached.clone();
+
+        Ok(thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let (command, arg) = match control::read_request(&stream) {
+                    Ok(request) => request,
+                    Err(err) => {
+                        log!(LogLevel::Error, "Failed to read from control socket: {:#}", err);
+                        continue;
+                    }
+                };
+
+                let reply = match command.as_str() {
+                    "status" => {
+                        let snapshot = state_snapshot.lock().unwrap();
+                        serde_json::to_string(&snapshot.to_json())
+                            .context("failed to serialize state")
+                    }
+                    "restart-child" => {
+                        let signal = match arg.as_str() {
+                            "" => Ok(stop_signal),
+                            arg => parse_signal(arg).map_err(anyhow::Error::msg),
+                        };
+                        signal.and_then(|signal| match *child_pid.lock().unwrap() {
+                            _ if restart_on == RestartOn::Never => Err(anyhow::anyhow!(
+                                "the restart policy is never, the child would not be respawned"
+                            )),
+                            Some(pid) => spawner
+                                .kill_group(pid, signal)
+                                .map(|_| pid.as_raw().to_string())
+                                .with_context(|| {
+                                    format!("failed to send {} to child {}", signal, pid)
+                                }),
+                            None => Err(anyhow::anyhow!("no child is running")),
+                        })
+                    }
+                    "reload" => {
+                        RELOAD_REQUESTED.store(true, std::sync::atomic::Ordering::SeqCst);
+                        Ok(String::new())
+                    }
+                    "signal" => {
+                        let (signal, unit) = arg.split_once(' ').unwrap_or((&arg, ""));
+                        parse_signal(signal)
+                            .map_err(anyhow::Error::msg)
+                            .and_then(|signal| {
+                                signal_processes(signal, unit, &child_pid, &unit_pids)
+                            })
+                            .map(|signaled| signaled.to_string())
+                    }
+                    "health" => match &health_query {
+                        Some((check, timeout)) => Ok(check.report(*timeout).to_string()),
+                        None => Err(anyhow::anyhow!("no health check is configured")),
+                    },
+                    "attach" if log_threads.lock().unwrap().is_empty() => Err(anyhow::anyhow!(
+                        "the output is not captured in --foreground mode"
+                    )),
+                    "attach" => Ok(String::new()),
+                    "stop" if arg.is_empty() => Ok(String::new()),
+                    "stop" => parse_signal(&arg)
+                        .map_err(anyhow::Error::msg)
+                        .and_then(|stop_signal| {
+                            std::fs::write(&stop_signal_file, stop_signal.as_str())
+                                .context("failed to write stop signal file")
+                        })
+                        .map(|_| String::new()),
+                    _ => Err(anyhow::anyhow!("unknown command: {}", command)),
+                };
+
+                if let Err(err) = control::write_reply(&stream, &reply) {
+                    log!(LogLevel::Error, "Failed to reply on control socket: {:#}", err);
+                }
+                // Shuts down the same way as on SIGTERM, once the client has its reply
+                if command == "stop" && reply.is_ok() {
+                    _ = kill(unistd::getpid(), Signal::SIGTERM);
+                }
+                if command == "attach"
+                    && reply.is_ok()
+                    && stream.set_write_timeout(Some(ATTACH_WRITE_TIMEOUT)).is_ok()
+                {
+                    attached.lock().unwrap().push(stream);
+                }
+            }
+        }))
+    }
+
+    /// Sends the child and units the stop signal on SIGTERM and SIGINT and ends the
+    /// supervision, which shuts the daemon down once the child exited
+    fn setup_signal_handler(&self, stop_signal: Signal) -> Result<()> {
+        let running = self.running.clone();
+        let child_pid = self.child_pid.clone();
+        let spawner = self.spawner.clone();
+        let unit_pids = self.unit_pids.clone();
+        let stop_signal_file = self.stop_signal_file.clone();
+        ctrlc::set_handler(move || {
+            // `stop --stop-signal` leaves the signal to use for this shutdown behind
+            let stop_signal = std::fs::read_to_string(&stop_signal_file)
+                .ok()
+                .and_then(|content| parse_signal(content.trim()).ok())
+                .unwrap_or(stop_signal);
+            let _ = std::fs::remove_file(&stop_signal_file);
+
+            running.store(false, std::sync::atomic::Ordering::SeqCst);
+            if let Some(pid) = child_pid.lock().unwrap().as_ref() {
+                _ = spawner.kill_group(*pid, stop_signal);
+            }
+            for pid in unit_pids.lock().unwrap().values() {
+                _ = spawner.kill_group(*pid, stop_signal);
+            }
+            log!(LogLevel::Info, "Received stop signal, shutting down...");
+            record_event(
+                "signal",
+                serde_json::json!({ "stop_signal": stop_signal.as_str() }),
+            );
+        })
+        .context("failed to set Ctrl-C handler")?;
+
+        // Replaces the shutdown ctrlc installs for SIGHUP, which now asks for a reload
+        let reload = SigAction::new(
+            SigHandler::Handler(request_reload),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGHUP, &reload) }.context("failed to set SIGHUP handler")?;
+
+        // Sent by logrotate after moving the logs away, it would otherwise kill the daemon
+        let reopen = SigAction::new(
+            SigHandler::Handler(request_log_reopen),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGUSR1, &reopen) }.context("failed to set SIGUSR1 handler")?;
+
+        // ctrlc covers SIGINT and SIGTERM, SIGQUIT would otherwise kill the daemon outright
+        let quit = SigAction::new(
+            SigHandler::Handler(forward_to_sigterm),
+            SaFlags::SA_RESTART,
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGQUIT, &quit) }.context("failed to set SIGQUIT handler")?;
+        Ok(())
+    }
+
+    fn status(&self, json: bool, unit: Option<&str>) -> Result<()> {
+        if let Some(name) = unit {
+            let pid = self.get_unit_pid(name)?;
+            let is_running = pid.is_running();
+            if json {
+                let status = serde_json::json!({
+                    "unit": name,
+                    "pid": pid.pid.as_raw(),
+                    "running": is_running,
+                });
+                println!("{}", status);
+            } else {
+                println!("Unit {} PID: {}, running: {}", name, pid, is_running);
+            }
+            return Ok(());
+        }
+
+        let (daemon_pid, child_pid) = self.get_pids()?;
+        let unit_pids = self.get_unit_pids()?;
+        // A daemon answering on the control socket can't be mistaken for an unrelated
+        // process that reused its PID, so only fall back to the PIDs without an answer
+        let live_state = control::request(&self.control_socket, "status")
+            .ok()
+            .flatten()
+            .and_then(|reply| serde_json::from_str::<serde_json::Value>(&reply).ok());
+        let (is_daemon_running, is_child_running) = match &live_state {
+            Some(state) => (
+                true,
+                state.get("state").and_then(|state| state.as_str()) == Some("running"),
+            ),
+            None => (daemon_pid.is_running(), child_pid.is_running()),
+        };
+        let stats = self.load_stats();
+        let gave_up = self.get_gave_up();
+        // The live state tells waiting between restarts apart from running, without a
+        // daemon the state file tells whether it gave up or was stopped
+        let state = match &live_state {
+            Some(state) => state
+                .get("state")
+                .and_then(|state| state.as_str())
+                .unwrap_or("running")
+                .to_string(),
+            None if gave_up.is_some() => "gave_up".to_string(),
+            None if is_daemon_running && is_child_running => "running".to_string(),
+            None if is_daemon_running => "restarting".to_string(),
+            None => "stopped".to_string(),
+        };
+
+        let run_info = self.get_run_info().ok();
+        // The rotations of the last daemon are still in its state.json once it stopped
+        let last_state = live_state.clone().or_else(|| {
+            std::fs::read_to_string(&self.state_json_file)
+                .ok()
+                .and_then(|content| serde_json::from_str::<serde_json::Value>(&content).ok())
+        });
+        let log_rotations = last_state
+            .as_ref()
+            .and_then(|state| state.get("log_rotations"))
+            .and_then(|rotations| rotations.as_u64())
+            .unwrap_or(0);
+        let last_log_rotation = last_state
+            .as_ref()
+            .and_then(|state| state.get("log_rotation_history"))
+            .and_then(|history| history.as_array())
+            .and_then(|history| history.last())
+            .and_then(|rotation| rotation.get("at"))
+            .and_then(|at| at.as_str())
+            .map(String::from);
+        let log_size = std::fs::metadata(&self.log_path)
+            .map(|metadata| metadata.len())
+            .ok();
+        let max_log_size_mib = run_info.as_ref().map(|run_info| run_info.max_log_size_mib);
+        // PID files written before the start time was recorded fall back to /proc
+        let started_at = run_info
+            .as_ref()
+            .and_then(|run_info| run_info.started_at.clone());
+        let uptime = is_daemon_running
+            .then(|| match &started_at {
+                Some(started_at) => chrono::DateTime::parse_from_rfc3339(started_at)
+                    .ok()
+                    .and_then(|started_at| (Utc::now() - started_at.to_utc()).to_std().ok()),
+                None => process_uptime(daemon_pid.pid),
+            })
+            .flatten();
+
+        if json {
+            let mut units = serde_json::Map::new();
+            for (name, pid) in &unit_pids {
+                units.insert(
+                    name.clone(),
+                    serde_json::json!({
+                        "pid": pid.pid.as_raw(),
+                        "running": pid.is_running(),
+                    }),
+                );
+            }
+            let status = serde_json::json!({
+                "state": state,
+                "command": run_info.map(|run_info| run_info.command),
+                "daemon_pid": daemon_pid.pid.as_raw(),
+                "child_pid": child_pid.pid.as_raw(),
+                "daemon_running": is_daemon_running,
+                "child_running": is_child_running,
+                "started_at": started_at,
+                "uptime_secs": uptime.map(|uptime| uptime.as_secs()),
+                "restarts": stats.restarts,
+                "last_exit_code": stats.last_exit_code,
+                "last_exit": stats.last_exit,
+                "last_usage": stats.last_usage.map(|usage| serde_json::json!({
+                    "user_cpu_secs": usage.user_time.as_secs_f64(),
+                    "system_cpu_secs": usage.system_time.as_secs_f64(),
+                    "max_rss_kib": usage.max_rss_kib,
+                })),
+                "last_restart": stats.last_restart,
+                "log_size": log_size,
+                "max_log_size_mib": max_log_size_mib,
+                "log_rotations": log_rotations,
+                "last_log_rotation": last_log_rotation,
+                "units": units,
+            });
+            println!("{}", status);
+            return ensure_daemon_running(daemon_pid, is_daemon_running);
+        }
+
+        println!("State: {}", state);
+        if let Some(run_info) = &run_info {
+            println!("Command: {}", quote_command(&run_info.command));
+        }
+        println!("Daemon PID: {}, running: {}", daemon_pid, is_daemon_running);
+        match (&started_at, uptime) {
+            (Some(started_at), Some(uptime)) => {
+                println!("Started: {}, up {}", started_at, format_uptime(uptime))
+            }
+            (None, Some(uptime)) => println!("Up {}", format_uptime(uptime)),
+            (_, None) => {}
+        }
+        println!("Child PID: {}, running: {}", child_pid, is_child_running);
+        if live_state.is_none() {
+            for (description, pid) in [("daemon", daemon_pid), ("child", child_pid)] {
+                if pid.state() == ProcessState::Unconfirmed {
+                    println!(
+                        "Warning: PID {} of the {} now belongs to another process",
+                        pid, description
+                    );
+                }
+            }
+        }
+        for (name, pid) in &unit_pids {
+            println!("Unit {} PID: {}, running: {}", name, pid, pid.is_running());
+        }
+        println!(
+            "Restarts: {}, last exit code: {}, last restart: {}",
+            stats.restarts,
+            stats
+                .last_exit_code
+                .map_or_else(|| "none".to_string(), |code| code.to_string()),
+            stats.last_restart.as_deref().unwrap_or("none")
+        );
+        if let Some(last_exit) = &stats.last_exit {
+            println!("Last exit: {}", last_exit);
+        }
+        if let Some(usage) = &stats.last_usage {
+            println!("Last run used: {}", usage);
+        }
+        if let Some((restarts, timestamp)) = gave_up {
+            println!("Daemon gave up after {} restarts at {}", restarts, timestamp);
+        }
+        if let Some(log_size) = log_size {
+            let max = max_log_size_mib.map_or_else(String::new, |max| format!(" of {} MiB", max));
+            println!(
+                "Log: {:.1} MiB{}, rotated {} times, last rotation: {}",
+                log_size as f64 / (1 << 20) as f64,
+                max,
+                log_rotations,
+                last_log_rotation.as_deref().unwrap_or("none")
+            );
+        }
+        ensure_daemon_running(daemon_pid, is_daemon_running)
+    }
+}
+
+/// Status is printed either way, but scripts checking it need a failing exit code
+fn ensure_daemon_running(daemon_pid: TrackedPid, is_running: bool) -> Result<()> {
+    if !is_running {
+        bail!(Failure::DaemonNotRunning(format!(
+            "Daemon {} is not running",
+            daemon_pid
+        )));
+    }
+    Ok(())
+}
+
+/// Prints the daemon and child PIDs, state and command of the guard of every status dir
+/// named `*.status.d` in `dir`. Guards whose daemon is gone are listed as dead
+fn list(dir: &Path, json: bool) -> Result<()> {
+    let mut status_dirs: Vec<PathBuf> = std::fs::read_dir(dir)
+        .with_context(|| format!("failed to list {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_name().to_string_lossy().ends_with(".status.d"))
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect();
+    status_dirs.sort();
+
+    let mut guards = Vec::new();
+    for status_dir in status_dirs {
+        let daemon = Daemon::new(Some(status_dir.clone()), StatusFiles::default())?;
+        // A dir whose guard never started has no PID file, there is nothing to list
+        let Ok((daemon_pid, child_pid)) = daemon.get_pids() else {
+            continue;
+        };
+        let state = match (daemon_pid.is_running(), child_pid.is_running()) {
+            (true, true) => "running",
+            (true, false) => "restarting",
+            (false, _) => "dead",
+        };
+        let command = daemon
+            .get_run_info()
+            .map(|run_info| quote_command(&run_info.command))
+            .unwrap_or_default();
+        guards.push((status_dir, daemon_pid, child_pid, state, command));
+    }
+
+    if json {
+        let guards: Vec<serde_json::Value> = guards
+            .iter()
+            .map(|(status_dir, daemon_pid, child_pid, state, command)| {
+                serde_json::json!({
+                    "status_dir": status_dir.display().to_string(),
+                    "daemon_pid": daemon_pid.pid.as_raw(),
+                    "child_pid": child_pid.pid.as_raw(),
+                    "state": *state,
+                    "command": command.clone(),
+                })
+            })
+            .collect();
+        println!("{}", serde_json::Value::from(guards));
+        return Ok(());
+    }
+
+    let width = guards
+        .iter()
+        .map(|(status_dir, ..)| status_dir.display().to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max("STATUS DIR".len());
+    println!(
+        "{:<width$}  {:>10}  {:>10}  {:<10}  COMMAND",
+        "STATUS DIR", "DAEMON PID", "CHILD PID", "STATE"
+    );
+    for (status_dir, daemon_pid, child_pid, state, command) in &guards {
+        println!(
+            "{:<width$}  {:>10}  {:>10}  {:<10}  {}",
+            status_dir.display().to_string(),
+            daemon_pid.pid,
+            child_pid.pid,
+            state,
+            command
+        );
+    }
+    Ok(())
+}
+
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+
+    /// The directory holding the PID, lock and log files [default: ./guarderd.status.d]
+    #[arg(long, global = true, env = "GUARDERD_STATUS_DIR")]
+    status_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    files: StatusFiles,
+
+    /// Only print messages of this level and above, given to start it applies to the daemon
+    #[arg(long, global = true, value_enum, default_value_t = LogLevel::Info)]
+    log_level: LogLevel,
+
+    /// Only print errors, the same as --log-level error
+    #[arg(short, long, global = true, conflicts_with = "log_level")]
+    quiet: bool,
+}
+
+/// Overrides of the file names in the status dir, for several guards sharing one dir
+#[derive(Args, Debug, Default)]
+struct StatusFiles {
+    /// The PID file, relative to the status dir [default: pid]
+    #[arg(long, global = true)]
+    pid_file: Option<PathBuf>,
+
+    /// The lock file, relative to the status dir [default: lock]
+    #[arg(long, global = true)]
+    lock_file: Option<PathBuf>,
+
+    /// The file capturing the child's output, relative to the status dir [default: stdout.log]
+    #[arg(long, global = true)]
+    log_file: Option<PathBuf>,
+}
+
+// Parsed once at startup, the size of StartArgs doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Start a new guard
+    Start(StartArgs),
+    /// Stop the guard
+    Stop(StopArgs),
+    /// Restart the guarded child, the daemon respawns it with the same command
+    Restart,
+    /// Make the daemon re-read its config file, like sending it SIGHUP
+    Reload,
+    /// Send a signal to the guarded child and units
+    Signal(SignalArgs),
+    /// Show the status of the guard
+    Status(StatusArgs),
+    /// Print the captured output of the child
+    Logs(LogsArgs),
+    /// Stream the live output of the child until Ctrl-C detaches
+    Attach,
+    /// Wait for the guarded child to exit and exit with its exit code
+    Wait(WaitArgs),
+    /// Probe the child once with the health check of the guard and print the outcome as
+    /// JSON, exiting with 0 only when it is healthy
+    Health,
+    /// Print where the guard keeps its files, whether or not it runs
+    Info(InfoArgs),
+    /// List the guards whose status dirs (*.status.d) are in a directory
+    #[command(alias = "ps")]
+    List(ListArgs),
+}
+
+#[derive(Args, Debug)]
+struct StartArgs {
+    /// The interval to restart the guard (e.g. 500ms, 30s or 5m, plain numbers are seconds)
+    /// [default: 5s]
+    #[arg(long, value_parser = parse_duration)]
+    restart_interval: Option<Duration>,
+
+    /// The command to run, unless the config file sets it
+    #[arg(last = true)]
+    command: Vec<String>,
+
+    /// Read the command from this file instead, one argument per line or a JSON array
+    /// ("-" reads stdin)
+    #[arg(long)]
+    command_file: Option<PathBuf>,
+
+    /// Run the command through `sh -c`, joined with spaces, so pipes, redirects and `&&`
+    /// work. The shell becomes the supervised child
+    #[arg(long)]
+    shell: bool,
+
+    /// Replace {restart_count}, {instance} and {daemon_pid} in the arguments of the command
+    /// on each spawn, {{ and }} stand for literal braces
+    #[arg(long)]
+    template_args: bool,
+
+    /// Also supervise a named unit (NAME=COMMAND, the command is split into arguments like
+    /// the shell does, quotes included), may be repeated
+    #[arg(long = "unit", value_parser = parse_unit)]
+    units: Vec<(String, Vec<String>)>,
+
+    /// Hold a listening socket open across restarts and pass it to the child like systemd
+    /// socket activation (HOST:PORT, or systemd for the sockets systemd passed to
+    /// guarderd), may be repeated
+    #[arg(long = "listen-fd", value_name = "ADDR", value_parser = listen::parse)]
+    listen_fds: Vec<listen::ListenSource>,
+
+    /// The maximum size of the log file (in MiB) [default: 10]
+    #[arg(long)]
+    max_log_size_mib: Option<u64>,
+
+    /// The number of gzip-compressed rotated logs to keep (0 discards old output on rotation)
+    #[arg(long, default_value_t = DEFAULT_LOG_KEEP)]
+    log_keep: u32,
+
+    /// Also rotate the log at every multiple of this interval (e.g. 12h, 1d), naming the
+    /// rotated logs after the time of rotation
+    #[arg(long, value_parser = parse_duration)]
+    log_rotate_interval: Option<Duration>,
+
+    /// Empty the log files on start instead of appending to the output of previous runs
+    #[arg(long)]
+    truncate_log_on_start: bool,
+
+    /// Write at most this many lines of output per second to the log, summing up the rest
+    /// in a line once the second is over (0 means unlimited)
+    #[arg(long, default_value_t = 0)]
+    log_max_lines_per_sec: u32,
+
+    /// How much output of the child (in KiB) is buffered while the log is written
+    #[arg(long, default_value_t = DEFAULT_LOG_BUFFER_KIB)]
+    log_buffer_kib: usize,
+
+    /// What happens to the output of the child once the buffer is full
+    #[arg(long, value_enum, default_value_t = LogOverflow::Block)]
+    log_overflow: LogOverflow,
+
+    /// How long a partial line held back by --timestamp-lines, --syslog or
+    /// --log-max-lines-per-sec waits for its newline before it is written as a line of its own
+    #[arg(long, value_parser = parse_duration, default_value = "2s")]
+    log_flush_interval: Duration,
+
+    /// The maximum size of the event journal, `events.jsonl` (in MiB)
+    #[arg(long, default_value_t = DEFAULT_JOURNAL_MAX_SIZE_MIB)]
+    journal_max_size_mib: u64,
+
+    /// The number of gzip-compressed rotated event journals to keep (0 discards old events
+    /// on rotation)
+    #[arg(long, default_value_t = DEFAULT_JOURNAL_KEEP)]
+    journal_keep: u32,
+
+    /// The grace period to consider the child process started successfully
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    grace_period: Duration,
+
+    /// Wait this long (e.g. 30s) after daemonizing before the first start of the child,
+    /// restarts aren't delayed
+    #[arg(long, value_parser = parse_duration)]
+    start_delay: Option<Duration>,
+
+    /// Double the restart interval after each consecutive fast failure, a clean exit or a
+    /// run of 60 seconds resets it
+    #[arg(long)]
+    backoff: bool,
+
+    /// The maximum restart interval when backoff is enabled
+    #[arg(long, value_parser = parse_duration, default_value = "5m")]
+    max_backoff: Duration,
+
+    /// Wait a random extra time up to this long before each restart, so that guards
+    /// restarting after the same outage don't all hit a dependency at once
+    #[arg(long, value_parser = parse_duration)]
+    restart_jitter: Option<Duration>,
+
+    /// The maximum number of consecutive failed restarts before giving up (0 means unlimited)
+    #[arg(long, default_value_t = 0)]
+    max_restarts: u64,
+
+    /// The maximum number of restarts within --restart-window before giving up, whether
+    /// they failed or not (0 means unlimited)
+    #[arg(long, default_value_t = 0)]
+    restart_limit: u32,
+
+    /// The sliding window --restart-limit counts restarts in
+    #[arg(long, value_parser = parse_duration, default_value = "60s")]
+    restart_window: Duration,
+
+    /// Count a child that exits before being up this long (e.g. 10s) as a failed restart,
+    /// whatever its exit status, and one that stayed up longer as recovered
+    #[arg(long, value_parser = parse_duration)]
+    min_uptime: Option<Duration>,
+
+    /// When to restart the child process after it exits
+    #[arg(long, value_enum, default_value_t = RestartOn::Always)]
+    restart_on: RestartOn,
+
+    /// Run the child a single time and exit the daemon with its exit code once it ends,
+    /// for one-shot jobs that still want their output captured
+    #[arg(long, conflicts_with_all = ["restart_on", "max_restarts", "restart_limit", "backoff"])]
+    once: bool,
+
+    /// Stop supervising and exit the daemon with code 0 once the child exits with code 0,
+    /// whatever --restart-on says
+    #[arg(long, conflicts_with = "once")]
+    stop_on_success: bool,
+
+    /// Only restart when the child exits with one of these codes (e.g. 1,2,75)
+    #[arg(long, value_delimiter = ',')]
+    restart_exit_codes: Vec<i32>,
+
+    /// Run in the foreground without daemonizing, keeping output on the terminal.
+    /// Ctrl-C still shuts down the guard and the child gracefully
+    #[arg(long)]
+    foreground: bool,
+
+    /// With --foreground, also write the output to the log files while still showing it
+    /// on the terminal
+    #[arg(long, requires = "foreground")]
+    tee: bool,
+
+    /// Stop the child and exit once the process that ran guarderd, such as the shell or
+    /// supervisor, exits
+    #[arg(long)]
+    exit_with_parent: bool,
+
+    /// Write the child's stderr to stderr.log instead of interleaving it into stdout.log
+    #[arg(long)]
+    separate_stderr: bool,
+
+    /// Run the child on a pseudo-terminal as its stdin, stdout and stderr, for programs
+    /// that buffer their output or refuse to run when it isn't a terminal
+    #[arg(long, conflicts_with_all = ["separate_stderr", "stdin_file"])]
+    pty: bool,
+
+    /// Prefix every captured output line with an RFC3339 timestamp
+    #[arg(long)]
+    timestamp_lines: bool,
+
+    /// Send every output line of the child to the local syslog (/dev/log) instead of the
+    /// log file, which is still written if syslog is unavailable
+    #[arg(long)]
+    syslog: bool,
+
+    /// The syslog facility of the output lines
+    #[arg(long, value_enum, default_value_t = syslog::Facility::User)]
+    syslog_facility: syslog::Facility,
+
+    /// The syslog tag of the output lines [default: the name of the program]
+    #[arg(long)]
+    syslog_tag: Option<String>,
+
+    /// The signal sent to the child when the guard shuts down (e.g. SIGINT, SIGQUIT)
+    #[arg(long, value_parser = parse_signal, default_value = "SIGTERM")]
+    stop_signal: Signal,
+
+    /// Send the child this signal --post-spawn-delay after every spawn (e.g. SIGCONT)
+    #[arg(long, value_parser = parse_signal)]
+    post_spawn_signal: Option<Signal>,
+
+    /// How long after each spawn --post-spawn-signal is sent (e.g. 500ms or 2s)
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        default_value = "1s",
+        requires = "post_spawn_signal"
+    )]
+    post_spawn_delay: Duration,
+
+    /// Set an environment variable for the child (KEY=VALUE), may be repeated
+    #[arg(long, value_parser = parse_env_var)]
+    env: Vec<(String, String)>,
+
+    /// Start the child from an empty environment instead of inheriting the daemon's
+    #[arg(long)]
+    env_clear: bool,
+
+    /// With --env-clear, pass this variable of guarderd's own environment on to the child,
+    /// may be repeated
+    #[arg(long = "env-passthrough", value_name = "KEY")]
+    env_passthrough: Vec<String>,
+
+    /// Load environment variables for the child from a dotenv-style file of KEY=VALUE lines
+    #[arg(long)]
+    env_file: Option<PathBuf>,
+
+    /// The working directory of the child [default: the directory guarderd was started from]
+    #[arg(long)]
+    workdir: Option<PathBuf>,
+
+    /// Where the stdin of the child comes from
+    #[arg(long, value_enum, default_value_t = StdinSource::Null)]
+    stdin: StdinSource,
+
+    /// Feed this file to the child as its stdin, read from the start on every restart
+    #[arg(long, value_name = "PATH", conflicts_with = "stdin")]
+    stdin_file: Option<PathBuf>,
+
+    /// Run the child as this user (name or uid), requires guarderd to run as root
+    #[arg(long)]
+    user: Option<String>,
+
+    /// Run the child with this group (name or gid) [default: the primary group of --user]
+    #[arg(long)]
+    group: Option<String>,
+
+    /// Limit the address space of the child (in bytes)
+    #[arg(long)]
+    limit_as: Option<u64>,
+
+    /// Limit the number of file descriptors the child may open
+    #[arg(long)]
+    limit_nofile: Option<u64>,
+
+    /// Limit the CPU time of the child (in seconds), it is sent SIGXCPU once it is used up
+    #[arg(long)]
+    limit_cpu: Option<u64>,
+
+    /// The nice value of the child, from -20 (highest priority) to 19 (lowest). Lowering it
+    /// below the one of guarderd requires root
+    #[arg(
+        long,
+        allow_negative_numbers = true,
+        value_parser = clap::value_parser!(i32).range(-20..=19)
+    )]
+    nice: Option<i32>,
+
+    /// The I/O scheduling class and level of the child, as CLASS[:LEVEL] (e.g. idle or
+    /// best-effort:7), realtime requires root
+    #[arg(long, value_parser = IoPriority::parse)]
+    ionice: Option<IoPriority>,
+
+    /// Place the child and units in this cgroup v2, created below the root of the unified
+    /// hierarchy unless it exists (e.g. guarderd/web). Ignored without cgroup v2
+    #[arg(long)]
+    cgroup: Option<PathBuf>,
+
+    /// Write a setting into the --cgroup, e.g. memory.max=512M or pids.max=100, enabling
+    /// its controller first. May be repeated
+    #[arg(long, value_parser = parse_env_var, requires = "cgroup")]
+    cgroup_set: Vec<(String, String)>,
+
+    /// The umask of the child, in octal (e.g. 027) [default: the umask of the daemon]
+    #[arg(long, value_parser = parse_umask)]
+    umask: Option<libc::mode_t>,
+
+    /// The umask of the daemon, for the PID, state and log files it creates
+    #[arg(long, value_parser = parse_umask)]
+    daemon_umask: Option<libc::mode_t>,
+
+    /// The permissions of the control socket, in octal (e.g. 660 to let the group stop,
+    /// restart and signal the child) [default: from the umask of the daemon]
+    #[arg(long, value_parser = parse_mode)]
+    socket_mode: Option<libc::mode_t>,
+
+    /// Periodically GET this http:// URL and restart the child when it keeps failing
+    #[arg(long, value_parser = HttpUrl::parse, group = "health")]
+    health_url: Option<HttpUrl>,
+
+    /// Periodically connect to this host:port and restart the child when it keeps failing
+    #[arg(long, value_parser = TcpAddr::parse, group = "health")]
+    health_tcp: Option<TcpAddr>,
+
+    /// Periodically run this shell command with the child's environment and working
+    /// directory, a non-zero exit counts as a failed check
+    #[arg(long, group = "health")]
+    health_cmd: Option<String>,
+
+    /// Restart the child when it stops touching this file, relative to its working
+    /// directory, for children that can write a file but don't expose a port
+    #[arg(long, group = "health")]
+    heartbeat_file: Option<PathBuf>,
+
+    /// How long ago the child may have touched the --heartbeat-file for a check to pass
+    #[arg(
+        long,
+        value_parser = parse_duration,
+        default_value = "30s",
+        requires = "heartbeat_file"
+    )]
+    heartbeat_timeout: Duration,
+
+    /// Run this shell command with the child's environment and working directory before
+    /// each spawn of the child
+    #[arg(long)]
+    pre_start: Option<String>,
+
+    /// Run this shell command with the child's environment and working directory after
+    /// each exit of the child, before it is restarted
+    #[arg(long)]
+    post_stop: Option<String>,
+
+    /// Shut down when a --pre-start or --post-stop hook fails instead of carrying on
+    #[arg(long)]
+    hooks_fatal: bool,
+
+    /// Run this shell command before each spawn of the child, after --pre-start, and only
+    /// spawn the child once it exits with 0. May be repeated, the stages run in order and
+    /// a failing one is handled like a child that failed to start
+    #[arg(long = "stage", value_name = "COMMAND")]
+    stages: Vec<String>,
+
+    /// The format of the PID file: keyvalue, which holds everything guarderd needs, or
+    /// simple, just the child PID for tools that expect a conventional pidfile
+    #[arg(long, value_enum, default_value_t = PidFileFormat::KeyValue)]
+    pidfile_format: PidFileFormat,
+
+    /// A TOML file of settings, overridden by flags and re-read on SIGHUP
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// If another daemon holds the lock, e.g. one still shutting down, retry for this long
+    /// (e.g. 10s) before giving up instead of failing right away
+    #[arg(long, value_parser = parse_duration)]
+    lock_wait: Option<Duration>,
+
+    /// Check the command, the status dir and the lock and print what would be started,
+    /// without starting anything
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Print each step of daemonizing and the PIDs it results in to stderr
+    #[arg(long, conflicts_with = "foreground")]
+    trace: bool,
+
+    /// Restart the child after reloading the config on SIGHUP
+    #[arg(long)]
+    hup_restarts_child: bool,
+
+    /// Restart the child after reloading the config on SIGHUP without downtime: start a
+    /// new one next to it and only stop the old one once the new one passes a health check
+    #[arg(long, conflicts_with = "once")]
+    overlap_restart: bool,
+
+    /// Restart the child when a file under this path changes (repeatable)
+    #[arg(long, value_name = "PATH")]
+    watch: Vec<PathBuf>,
+
+    /// How long watched files have to stay unchanged (e.g. 500ms or 2s) before the child is
+    /// restarted, so that a burst of changes restarts it once
+    #[arg(long, value_parser = parse_duration, default_value = "500ms")]
+    watch_debounce: Duration,
+
+    /// Notify systemd (Type=notify) once the child is up, after its first passing health
+    /// check if one is configured, and ping the watchdog if WATCHDOG_USEC is set
+    #[arg(long)]
+    sd_notify: bool,
+
+    /// POST a JSON event to this http:// URL when the child exits unexpectedly and when it
+    /// is restarted
+    #[arg(long, value_parser = HttpUrl::parse)]
+    notify_webhook: Option<HttpUrl>,
+
+    /// Serve Prometheus metrics on this host:port at /metrics
+    #[arg(long, value_name = "HOST:PORT")]
+    metrics_addr: Option<String>,
+
+    /// The interval between health checks, also the timeout of each check
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    health_interval: Duration,
+
+    /// The number of consecutive failed health checks that trigger a restart
+    #[arg(long, default_value_t = DEFAULT_HEALTH_FAILURES)]
+    health_failures: u32,
+
+    /// The time after each spawn before health checks begin
+    #[arg(long, value_parser = parse_duration, default_value = "10s")]
+    health_start_delay: Duration,
+
+    /// Treat the child as failed to start when it doesn't pass a health check within this
+    /// time after each spawn (e.g. 30s or 2m), stopping it and applying the restart policy
+    #[arg(long, value_parser = parse_duration)]
+    ready_timeout: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+struct StopArgs {
+    /// The time the child gets to exit before it is killed with SIGKILL (e.g. 30s)
+    #[arg(long, value_parser = parse_duration, default_value = "5s")]
+    stop_timeout: Duration,
+
+    /// The time the daemon gets to exit before it is killed with SIGKILL
+    #[arg(long, value_parser = parse_duration, default_value = "1s")]
+    daemon_timeout: Duration,
+
+    /// The signal the daemon sends the child to stop it, overriding the one given to start
+    #[arg(long, value_parser = parse_signal)]
+    stop_signal: Option<Signal>,
+
+    /// Only stop this unit, the daemon and everything else keep running
+    #[arg(long)]
+    unit: Option<String>,
+
+    /// Only stop the child with its stop signal, the daemon keeps running and respawns it
+    #[arg(long, conflicts_with = "unit")]
+    child_only: bool,
+
+    /// Fail with exit code 4 when no daemon is running instead of succeeding
+    #[arg(long)]
+    strict: bool,
+}
+
+#[derive(Args, Debug)]
+struct SignalArgs {
+    /// The signal to send, e.g. SIGUSR1
+    #[arg(value_parser = parse_signal)]
+    signal: Signal,
+
+    /// Only signal this unit [default: the child and all units]
+    #[arg(long)]
+    unit: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct LogsArgs {
+    /// The number of lines to print from the end of the log
+    #[arg(short = 'n', long, default_value_t = 10)]
+    lines: usize,
+
+    /// Keep printing output as it is appended, following the log across rotations
+    #[arg(short, long)]
+    follow: bool,
+}
+
+#[derive(Args, Debug)]
+struct WaitArgs {
+    /// Give up waiting after this long (e.g. 30s or 5m) and exit with code 5
+    #[arg(long, value_parser = parse_duration)]
+    timeout: Option<Duration>,
+}
+
+#[derive(Args, Debug)]
+struct StatusArgs {
+    /// Print the status as a JSON object
+    #[arg(long)]
+    json: bool,
+
+    /// Only show the status of this unit
+    #[arg(long)]
+    unit: Option<String>,
+}
+
+#[derive(Args, Debug)]
+struct InfoArgs {
+    /// Print the paths as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct ListArgs {
+    /// The directory holding the status dirs
+    #[arg(long, default_value = ".")]
+    dir: PathBuf,
+
+    /// Print the guards as a JSON array
+    #[arg(long)]
+    json: bool,
+}
+
+impl StartArgs {
+    /// Fills in the settings from the config file that weren't given on the command line
+    fn merge_config(&mut self, config: &Config, matches: &ArgMatches) -> Result<()> {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        if let Some(command) = &config.command {
+            if !self.command.is_empty() {
+                bail!("the command is set in the config file, it can't also be given after --");
+            }
+            self.command = command.clone();
+        }
+        if let Some(path) = &self.command_file {
+            if !self.command.is_empty() {
+                bail!(
+                    "the command is read from --command-file, it can't also be given after -- \
+                     or in the config file"
+                );
+            }
+            self.command = load_command_file(path)?;
+        }
+        if self.command.is_empty() {
+            bail!(
+                "no command to run, give it after --, with --command-file or in the config file"
+            );
+        }
+
+        macro_rules! merge {
+            ($($field:ident),* $(,)?) => {$(
+                if let Some(value) = &config.$field
+                    && !from_cli(stringify!($field))
+                {
+                    self.$field = value.clone().into();
+                }
+            )*};
+        }
+
+        // restart_interval and max_log_size_mib are resolved against the config on every
+        // reload instead, see restart_interval() and max_log_size_mib()
+        merge!(
+            log_keep,
+            log_rotate_interval,
+            truncate_log_on_start,
+            log_buffer_kib,
+            log_max_lines_per_sec,
+            log_overflow,
+            log_flush_interval,
+            journal_max_size_mib,
+            journal_keep,
+            grace_period,
+            start_delay,
+            lock_wait,
+            backoff,
+            max_backoff,
+            restart_jitter,
+            max_restarts,
+            restart_limit,
+            restart_window,
+            min_uptime,
+            restart_on,
+            once,
+            stop_on_success,
+            restart_exit_codes,
+            foreground,
+            tee,
+            exit_with_parent,
+            metrics_addr,
+            separate_stderr,
+            pty,
+            timestamp_lines,
+            syslog,
+            syslog_facility,
+            syslog_tag,
+            env_clear,
+            env_passthrough,
+            env_file,
+            workdir,
+            stdin,
+            stdin_file,
+            pidfile_format,
+            user,
+            group,
+            limit_as,
+            limit_nofile,
+            limit_cpu,
+            nice,
+            post_spawn_delay,
+            cgroup,
+            health_interval,
+            health_failures,
+            health_start_delay,
+            ready_timeout,
+            heartbeat_timeout,
+            pre_start,
+            post_stop,
+            hooks_fatal,
+            stages,
+            hup_restarts_child,
+            overlap_restart,
+            watch,
+            watch_debounce,
+            sd_notify,
+            shell,
+            template_args,
+        );
+
+        // The arguments are joined as they are and parsed by the shell once more
+        if self.shell {
+            self.command = vec!["sh".to_string(), "-c".to_string(), self.command.join(" ")];
+        }
+        // Checked up front, a mistake would otherwise only fail the spawns
+        if self.template_args {
+            for arg in &self.command {
+                expand_placeholders(arg, &Placeholders::default()).map_err(anyhow::Error::msg)?;
+            }
+        }
+
+        if let Some(sources) = &config.listen_fds
+            && !from_cli("listen_fds")
+        {
+            self.listen_fds = sources
+                .iter()
+                .map(|source| listen::parse(source))
+                .collect::<Result<_, _>>()
+                .map_err(anyhow::Error::msg)?;
+        }
+
+        if let Some(ionice) = &config.ionice
+            && !from_cli("ionice")
+        {
+            self.ionice = Some(IoPriority::parse(ionice).map_err(anyhow::Error::msg)?);
+        }
+        if let Some(umask) = &config.umask
+            && !from_cli("umask")
+        {
+            self.umask = Some(parse_umask(umask).map_err(anyhow::Error::msg)?);
+        }
+        if let Some(umask) = &config.daemon_umask
+            && !from_cli("daemon_umask")
+        {
+            self.daemon_umask = Some(parse_umask(umask).map_err(anyhow::Error::msg)?);
+        }
+        if let Some(mode) = &config.socket_mode
+            && !from_cli("socket_mode")
+        {
+            self.socket_mode = Some(parse_mode(mode).map_err(anyhow::Error::msg)?);
+        }
+
+        if self.log_rotate_interval == Some(Duration::ZERO) {
+            bail!("the log rotation interval must be greater than zero");
+        }
+        if self.log_buffer_kib == 0 {
+            bail!("the log buffer size must be greater than zero");
+        }
+        if self.log_flush_interval.is_zero() {
+            bail!("the log flush interval must be greater than zero");
+        }
+        if self.log_buffer_kib > MAX_LOG_BUFFER_KIB {
+            bail!(
+                "the log buffer size can be at most {} KiB",
+                MAX_LOG_BUFFER_KIB
+            );
+        }
+        // Checked once merged, either may come from the config file
+        if !self.env_passthrough.is_empty() && !self.env_clear {
+            bail!("--env-passthrough needs --env-clear, the whole environment is passed otherwise");
+        }
+        if self.health_failures == 0 {
+            bail!("--health-failures must be greater than zero");
+        }
+        if self.health_interval.is_zero() {
+            bail!("--health-interval must be greater than zero");
+        }
+
+        if let Some(stop_signal) = &config.stop_signal
+            && !from_cli("stop_signal")
+        {
+            self.stop_signal = parse_signal(stop_signal).map_err(anyhow::Error::msg)?;
+        }
+
+        if let Some(signal) = &config.post_spawn_signal
+            && !from_cli("post_spawn_signal")
+        {
+            self.post_spawn_signal = Some(parse_signal(signal).map_err(anyhow::Error::msg)?);
+        }
+        if let Some(signal) = self.post_spawn_signal {
+            // Neither can be handled, the child would be killed or frozen on every spawn
+            if matches!(signal, Signal::SIGKILL | Signal::SIGSTOP) {
+                bail!("--post-spawn-signal can't be {}, the child can't handle it", signal);
+            }
+            // A signal arriving before the child installed its handlers would kill it
+            if self.post_spawn_delay.is_zero() {
+                bail!("--post-spawn-delay must be greater than zero");
+            }
+        }
+
+        // Only one health check is used, one given on the command line replaces the file's
+        let configured = [
+            config.health_url.is_some(),
+            config.health_tcp.is_some(),
+            config.health_cmd.is_some(),
+            config.heartbeat_file.is_some(),
+        ];
+        if configured.iter().filter(|&&set| set).count() > 1 {
+            bail!(
+                "only one of health_url, health_tcp, health_cmd and heartbeat_file may be set in \
+                 the config file"
+            );
+        }
+        let health_ids = ["health_url", "health_tcp", "health_cmd", "heartbeat_file"];
+        if !health_ids.iter().any(|id| from_cli(id)) {
+            if let Some(url) = &config.health_url {
+                self.health_url = Some(HttpUrl::parse(url).map_err(anyhow::Error::msg)?);
+            }
+            if let Some(addr) = &config.health_tcp {
+                self.health_tcp = Some(TcpAddr::parse(addr).map_err(anyhow::Error::msg)?);
+            }
+            self.health_cmd = config.health_cmd.clone();
+            self.heartbeat_file = config.heartbeat_file.clone();
+        }
+
+        if let Some(timeout) = self.ready_timeout {
+            if !self.has_health_check() {
+                bail!("--ready-timeout needs a health check to tell when the child is ready");
+            }
+            if timeout.is_zero() {
+                bail!("--ready-timeout must be greater than zero");
+            }
+        }
+
+        // The terminal carries stdout and stderr together, and is the stdin of the child
+        if self.pty {
+            if self.separate_stderr {
+                bail!("--pty can't be combined with --separate-stderr");
+            }
+            if self.stdin == StdinSource::Inherit || self.stdin_file.is_some() {
+                bail!("--pty can't be combined with --stdin inherit or --stdin-file");
+            }
+            if self.foreground && !self.tee {
+                bail!("--pty needs --tee with --foreground, whose output isn't captured otherwise");
+            }
+        }
+
+        if self.overlap_restart && !self.has_health_check() {
+            bail!("--overlap-restart needs a health check to tell when the new child is ready");
+        }
+        // Both children would share the file, the old one keeping it fresh for the new one
+        if self.overlap_restart && self.heartbeat_file.is_some() {
+            bail!("--overlap-restart can't be combined with --heartbeat-file");
+        }
+
+        if let Some(url) = &config.notify_webhook
+            && !from_cli("notify_webhook")
+        {
+            self.notify_webhook = Some(HttpUrl::parse(url).map_err(anyhow::Error::msg)?);
+        }
+
+        let mut names = BTreeSet::new();
+        for (name, _) in &self.units {
+            if !names.insert(name.clone()) {
+                bail!("unit {} is given more than once", name);
+            }
+        }
+        // Units given with --unit replace the file's ones of the same name
+        if let Some(units) = &config.units {
+            for (name, command) in units {
+                validate_unit_name(name).map_err(anyhow::Error::msg)?;
+                if command.is_empty() {
+                    bail!("missing command for unit {} in the config file", name);
+                }
+                if !names.contains(name) {
+                    self.units.push((name.clone(), command.clone()));
+                }
+            }
+        }
+
+        // The settings given with --cgroup-set are written last so they win over the file's
+        if let Some(settings) = &config.cgroup_set {
+            let cli_settings = std::mem::take(&mut self.cgroup_set);
+            self.cgroup_set = settings.clone().into_iter().chain(cli_settings).collect();
+        }
+
+        // Variables given with --env are applied last so they win over the file's
+        if let Some(env) = &config.env {
+            let cli_env = std::mem::take(&mut self.env);
+            self.env = env.clone().into_iter().chain(cli_env).collect();
+        }
+
+        Ok(())
+    }
+
+    fn restart_interval(&self, config: &Config) -> Duration {
+        self.restart_interval
+            .or(config.restart_interval)
+            .unwrap_or(DEFAULT_RESTART_INTERVAL)
+    }
+
+    fn max_log_size_mib(&self, config: &Config) -> u64 {
+        self.max_log_size_mib
+            .or(config.max_log_size_mib)
+            .unwrap_or(DEFAULT_MAX_LOG_SIZE_MIB)
+    }
+
+    /// Connects to syslog for --syslog, falling back to the log file if that fails
+    fn syslog(&self, severity: syslog::Severity) -> Option<syslog::Syslog> {
+        if !self.syslog {
+            return None;
+        }
+        let tag = match &self.syslog_tag {
+            Some(tag) => tag.clone(),
+            None => syslog::default_tag(&self.command[0]),
+        };
+        syslog::Syslog::connect(self.syslog_facility, severity, &tag)
+            .inspect_err(|err| {
+                log!(
+                    LogLevel::Warn,
+                    "Syslog is unavailable, writing output to the log file instead: {}",
+                    err
+                );
+            })
+            .ok()
+    }
+
+    fn has_health_check(&self) -> bool {
+        self.health_url.is_some()
+            || self.health_tcp.is_some()
+            || self.health_cmd.is_some()
+            || self.heartbeat_file.is_some()
+    }
+
+    /// The health check selected by the --health-* and --heartbeat-* options, if any
+    fn health_check(&self, child_config: &ChildConfig) -> Option<HealthCheck> {
+        if let Some(url) = &self.health_url {
+            return Some(HealthCheck::Http(url.clone()));
+        }
+        if let Some(addr) = &self.health_tcp {
+            return Some(HealthCheck::Tcp(addr.clone()));
+        }
+        if let Some(command) = &self.health_cmd {
+            return Some(HealthCheck::Exec(ExecProbe {
+                command: command.clone(),
+                env_clear: self.env_clear,
+                env: child_config.env(self).cloned().collect(),
+                workdir: child_config.workdir.clone(),
+            }));
+        }
+        if let Some(path) = &self.heartbeat_file {
+            return Some(HealthCheck::Heartbeat(Heartbeat {
+                path: child_config.workdir.join(path),
+                timeout: self.heartbeat_timeout,
+                since: None,
+            }));
+        }
+        None
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum RestartOn {
+    /// Restart regardless of how the child exited
+    Always,
+    /// Restart only if the child exited with a non-zero code or was killed by a signal
+    OnFailure,
+    /// Never restart the child
+    Never,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum LogOverflow {
+    /// Wait for the log, the child blocks on writing its output until there is room again
+    Block,
+    /// Drop the lines that don't fit, the child keeps running but the log has gaps. A line
+    /// whose start was already written, e.g. a long one, is cut short instead
+    Drop,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+enum PidFileFormat {
+    /// `daemon_pid: 1234` lines with the start times, units and settings of the guard
+    #[value(name = "keyvalue")]
+    #[serde(rename = "keyvalue")]
+    KeyValue,
+    /// Just the PID of the child on one line, like conventional pidfiles. The rest goes to
+    /// the run_info file next to it
+    #[value(name = "simple")]
+    #[serde(rename = "simple")]
+    Simple,
+}
+
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum StdinSource {
+    /// Read from /dev/null, the child sees end of file right away
+    Null,
+    /// Share the stdin guarderd was started with, mostly useful with --foreground
+    Inherit,
+}
+
+fn main() -> ExitCode {
+    match run() {
+        Ok(code) => code,
+        Err(err) => {
+            eprintln!("Error: {:?}", err);
+            ExitCode::from(err.downcast_ref::<Failure>().map_or(1, Failure::exit_code))
+        }
+    }
+}
+
+fn run() -> Result<ExitCode> {
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    let log_level = if cli.quiet { LogLevel::Error } else { cli.log_level };
+    LOG_LEVEL.store(log_level as u8, std::sync::atomic::Ordering::Relaxed);
+    let mut daemon = Daemon::new(cli.status_dir, cli.files)?;
+    match cli.command {
+        Commands::Start(mut args) => {
+            let config = match &args.config {
+                Some(path) => Config::load(path)?,
+                None => Config::default(),
+            };
+            let start_matches = matches
+                .subcommand_matches("start")
+                .expect("start subcommand was parsed");
+            args.merge_config(&config, start_matches)?;
+            return daemon.start(args, config);
+        }
+        Commands::Stop(args) if args.child_only => {
+            daemon.restart_child(args.stop_signal)?;
+        }
+        Commands::Stop(args) => {
+            let stopped = match &args.unit {
+                Some(name) => daemon.stop_unit(name, args.stop_timeout, args.stop_signal),
+                None => daemon.stop(args.daemon_timeout, args.stop_timeout, args.stop_signal),
+            };
+            match stopped {
+                // A guard that isn't running is as stopped as it gets
+                Err(err)
+                    if !args.strict
+                        && matches!(err.downcast_ref(), Some(Failure::DaemonNotRunning(_))) =>
+                {
+                    if log_enabled(LogLevel::Info) {
+                        println!("Nothing to stop: {}", err);
+                    }
+                }
+                stopped => stopped?,
+            }
+        }
+        Commands::Restart => {
+            daemon.restart_child(None)?;
+        }
+        Commands::Reload => {
+            daemon.reload()?;
+        }
+        Commands::Signal(args) => {
+            daemon.signal(args.signal, args.unit.as_deref())?;
+        }
+        Commands::Status(args) => {
+            daemon.status(args.json, args.unit.as_deref())?;
+        }
+        Commands::Logs(args) => {
+            daemon.logs(args.lines, args.follow)?;
+        }
+        Commands::Wait(args) => {
+            return daemon.wait(args.timeout);
+        }
+        Commands::Attach => {
+            daemon.attach()?;
+        }
+        Commands::Health => {
+            return daemon.health();
+        }
+        Commands::Info(args) => {
+            daemon.info(args.json)?;
+        }
+        Commands::List(args) => {
+            list(&args.dir, args.json)?;
+        }
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use spawner::scripted::ScriptedSpawner;
+
+    /// Runs the supervision loop of a guard started with `flags` against the scripted
+    /// spawner and its clock, in a status dir of its own, until it shuts down or gives up
+    fn supervise(name: &str, flags: &[&str], spawner: &Arc<ScriptedSpawner>) -> (Daemon, ExitCode) {
+        let argv = ["guarderd", "start"]
+            .iter()
+            .chain(flags)
+            .chain(&["--", "true"]);
+        let Commands::Start(args) = Cli::try_parse_from(argv).unwrap().command else {
+            unreachable!("parsed as start");
+        };
+        let status_dir =
+            std::env::temp_dir().join(format!("guarderd-test-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&status_dir);
+        let mut daemon = Daemon::new(Some(status_dir), StatusFiles::default()).unwrap();
+        daemon.spawner = spawner.clone();
+        daemon.clock = spawner.clock();
+        let config = Config::default();
+        daemon.apply_config(&args, &config);
+        daemon
+            .running
+            .store(true, std::sync::atomic::Ordering::SeqCst);
+
+        let child_config = ChildConfig::resolve(&args, &daemon.launch_dir).unwrap();
+        let run_info = RunInfo::from_args(&args, &config);
+        let cnt = AtomicU64::new(0);
+        let takeover = Mutex::new(None);
+        let run = ChildRun {
+            daemon_pid: unistd::getpid(),
+            run_info: &run_info,
+            listen_fds: 0,
+            max_backoff: args.backoff.then_some(args.max_backoff),
+            grace_deadline: daemon.clock.now(),
+            cnt: &cnt,
+            takeover: &takeover,
+            notify_ready: false,
+        };
+        let exit_code = daemon.supervise_child(&args, &child_config, run);
+        (daemon, exit_code)
+    }
+
+    #[test]
+    fn restarts_a_crashed_child() {
+        let spawner = Arc::new(ScriptedSpawner::new([1, 0]));
+        let flags = ["--restart-interval", "10ms", "--stop-on-success"];
+        let (daemon, exit_code) = supervise("restart", &flags, &spawner);
+
+        assert_eq!(spawner.spawns().len(), 2);
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+        assert_eq!(daemon.load_stats().restarts, 1);
+        assert!(daemon.get_gave_up().is_none());
+    }
+
+    /// The time between consecutive spawns, the uptime of a run plus the wait to restart it
+    fn spawn_gaps(spawner: &ScriptedSpawner) -> Vec<Duration> {
+        let spawns = spawner.spawns();
+        spawns.windows(2).map(|w| w[1] - w[0]).collect()
+    }
+
+    #[test]
+    fn doubles_the_restart_interval_with_backoff() {
+        let spawner = Arc::new(ScriptedSpawner::new([1, 1, 1, 1, 0]));
+        let flags = [
+            "--restart-interval",
+            "50ms",
+            "--backoff",
+            "--max-backoff",
+            "150ms",
+            "--stop-on-success",
+        ];
+        supervise("backoff", &flags, &spawner);
+
+        // 50ms, doubled to 100ms, then capped at 150ms instead of 200ms
+        let expected = [50, 100, 150, 150].map(Duration::from_millis);
+        assert_eq!(spawn_gaps(&spawner), expected);
+    }
+
+    #[test]
+    fn resets_the_backoff_after_a_stable_run() {
+        let stable = BACKOFF_RESET_UPTIME;
+        let spawner = Arc::new(ScriptedSpawner::with_uptimes([
+            (1, Duration::ZERO),
+            (1, Duration::ZERO),
+            (1, stable),
+            (1, Duration::ZERO),
+            (0, Duration::ZERO),
+        ]));
+        let flags = [
+            "--restart-interval",
+            "50ms",
+            "--backoff",
+            "--stop-on-success",
+        ];
+        supervise("backoff-reset", &flags, &spawner);
+
+        let expected = [
+            Duration::from_millis(50),
+            Duration::from_millis(100),
+            stable + Duration::from_millis(50),
+            Duration::from_millis(50),
+        ];
+        assert_eq!(spawn_gaps(&spawner), expected);
+    }
+
+    #[test]
+    fn resets_the_backoff_after_a_clean_exit() {
+        let spawner = Arc::new(ScriptedSpawner::new([1, 1, 0, 1, 1, 3]));
+        let flags = [
+            "--restart-interval",
+            "50ms",
+            "--backoff",
+            "--restart-exit-codes",
+            "0,1",
+        ];
+        supervise("backoff-clean", &flags, &spawner);
+
+        let expected = [50, 100, 50, 50, 100].map(Duration::from_millis);
+        assert_eq!(spawn_gaps(&spawner), expected);
+    }
+
+    #[test]
+    fn gives_up_after_max_restarts() {
+        let spawner = Arc::new(ScriptedSpawner::new([3, 3, 3, 3]));
+        let flags = ["--restart-interval", "10ms", "--max-restarts", "2"];
+        let (daemon, exit_code) = supervise("give-up", &flags, &spawner);
+
+        // The initial run and two restarts, the last of the script is never spawned
+        assert_eq!(spawner.spawns().len(), 3);
+        assert_eq!(exit_code, ExitCode::from(3));
+        assert_eq!(
+            daemon.get_gave_up().map(|(restarts, _)| restarts),
+            Some("2".into())
+        );
+        assert!(!daemon.running.load(std::sync::atomic::Ordering::SeqCst));
+    }
+
+    #[test]
+    fn gives_up_after_restart_limit_within_the_window() {
+        let spawner = Arc::new(ScriptedSpawner::new([1, 1, 1, 1]));
+        let flags = [
+            "--restart-interval",
+            "10ms",
+            "--restart-limit",
+            "2",
+            "--restart-window",
+            "1m",
+        ];
+        let (daemon, exit_code) = supervise("restart-limit", &flags, &spawner);
+
+        // Two restarts are allowed, the third within the minute gives up
+        assert_eq!(spawner.spawns().len(), 3);
+        assert_eq!(exit_code, ExitCode::from(1));
+        assert_eq!(
+            daemon.get_gave_up().map(|(restarts, _)| restarts),
+            Some("2".into())
+        );
+    }
+
+    #[test]
+    fn forgets_restarts_outside_the_restart_window() {
+        let slow = Duration::from_secs(40);
+        let spawner = Arc::new(ScriptedSpawner::with_uptimes([
+            (1, slow),
+            (1, slow),
+            (1, slow),
+            (0, Duration::ZERO),
+        ]));
+        let flags = [
+            "--restart-interval",
+            "10ms",
+            "--restart-limit",
+            "2",
+            "--restart-window",
+            "1m",
+            "--stop-on-success",
+        ];
+        let (daemon, exit_code) = supervise("restart-window", &flags, &spawner);
+
+        // Each restart after the second finds the first one out of the window
+        assert_eq!(spawner.spawns().len(), 4);
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+        assert!(daemon.get_gave_up().is_none());
+    }
+
+    #[test]
+    fn restarts_only_failures_on_failure() {
+        let spawner = Arc::new(ScriptedSpawner::new([2, 1, 0, 1]));
+        let flags = ["--restart-interval", "10ms", "--restart-on", "on-failure"];
+        let (daemon, exit_code) = supervise("on-failure", &flags, &spawner);
+
+        // The clean exit ends the supervision, the last of the script is never spawned
+        assert_eq!(spawner.spawns().len(), 3);
+        assert_eq!(exit_code, ExitCode::SUCCESS);
+        assert_eq!(daemon.load_stats().restarts, 2);
+        assert!(daemon.get_gave_up().is_none());
+    }
+
+    #[test]
+    fn restarts_only_the_listed_exit_codes() {
+        let spawner = Arc::new(ScriptedSpawner::new([75, 0, 75, 3, 75]));
+        let flags = ["--restart-interval", "10ms", "--restart-exit-codes", "0,75"];
+        let (daemon, exit_code) = supervise("exit-codes", &flags, &spawner);
+
+        // Even a clean exit is restarted when it is listed, 3 isn't
+        assert_eq!(spawner.spawns().len(), 4);
+        assert_eq!(exit_code, ExitCode::from(3));
+        assert_eq!(daemon.load_stats().restarts, 3);
+    }
+
+    #[test]
+    fn splits_unit_commands_like_the_shell() {
+        let (name, command) =
+            parse_unit(r#"worker=python -c 'print("a b")' "x \"y\" \z" a\ b """#).unwrap();
+        assert_eq!(name, "worker");
+        assert_eq!(
+            command,
+            ["python", "-c", r#"print("a b")"#, r#"x "y" \z"#, "a b", ""]
+        );
+        assert!(parse_unit("worker=sh -c 'exit").is_err());
+        assert!(parse_unit("worker=  ").is_err());
+    }
+
+    #[test]
+    fn signals_the_group_of_a_child_after_it_exited() {
+        use std::os::unix::process::CommandExt;
+        let pid_file =
+            std::env::temp_dir().join(format!("guarderd-test-{}-orphan", std::process::id()));
+        let spawner = OsSpawner::default();
+        let mut command = Command::new("sh");
+        command
+            .args(["-c", "sleep 60 & echo $! >\"$0\""])
+            .arg(&pid_file)
+            .process_group(0);
+        let mut child = spawner.spawn(&mut command).unwrap();
+        let leader = Pid::from_raw(child.id() as i32);
+        child.wait().unwrap();
+        let orphan = std::fs::read_to_string(&pid_file).unwrap();
+        let _ = std::fs::remove_file(&pid_file);
+
+        spawner.kill_group(leader, Signal::SIGKILL).unwrap();
+        // Exited once it is a zombie waiting for init or gone
+        let exited = || {
+            std::fs::read_to_string(format!("/proc/{}/stat", orphan.trim()))
+                .map_or(true, |stat| stat.contains(") Z "))
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !exited() && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(exited(), "the process the child left behind wasn't killed");
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("15m"), Ok(Duration::from_secs(15 * 60)));
+        assert_eq!(parse_duration("12h"), Ok(Duration::from_secs(12 * 60 * 60)));
+        assert_eq!(parse_duration("1d"), Ok(Duration::from_secs(24 * 60 * 60)));
+        assert!(parse_duration("99999999999999d").is_err());
+        assert!(parse_duration("18446744073709551616").is_err());
+        assert!(parse_duration("5w").is_err());
+        assert!(parse_duration("s").is_err());
+    }
+
+    /// A pipe that takes writes whole while they fit, like pipes do up to PIPE_BUF bytes
+    struct FullPipe {
+        written: Vec<u8>,
+        room: usize,
+    }
+
+    impl Write for FullPipe {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if buf.len() > self.room {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+            self.room -= buf.len();
+            self.written.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drops_whole_lines_when_the_log_pipe_is_full() {
+        let mut pipe = FullPipe {
+            written: Vec::new(),
+            room: 12,
+        };
+        let mut state = RelayState::Writing { mid_line: false };
+        let dropped = relay_lines(&mut pipe, b"first\nsecond\nthe th", &mut state).unwrap();
+        assert_eq!(dropped, 7);
+        assert_eq!(pipe.written, b"first\nthe th");
+
+        // The start of the third line is in the log, so it is cut short with a newline
+        pipe.room = 0;
+        let dropped = relay_lines(&mut pipe, b"ird\nfourth\n", &mut state).unwrap();
+        assert_eq!(dropped, 11);
+        pipe.room = 100;
+        let dropped = relay_lines(&mut pipe, b"fifth\n", &mut state).unwrap();
+        assert_eq!(dropped, 0);
+        assert_eq!(pipe.written, b"first\nthe th\nfifth\n");
+    }
+
+    #[test]
+    fn keeps_apart_timestamped_rotations_within_a_second() {
+        let dir = std::env::temp_dir().join(format!("guarderd-test-{}-stamps", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("stdout.log");
+        let mut rotated = Vec::new();
+        for _ in 0..4 {
+            std::fs::write(&path, "output\n").unwrap();
+            let (staged, compressed) = rotate_log_timestamped(&path, 2).unwrap();
+            compress_log(&staged, &compressed).unwrap();
+            rotated.push(compressed);
+        }
+
+        // None replaced another, and only the latest two are kept
+        rotated.dedup();
+        assert_eq!(rotated.len(), 4);
+        let kept: Vec<bool> = rotated.iter().map(|path| path.exists()).collect();
+        assert_eq!(kept, [false, false, true, true]);
+        let time = "20250101-000000";
+        assert_eq!(parse_rotation_stamp(time), Some((time, 0)));
+        assert_eq!(
+            parse_rotation_stamp(&format!("{}-12", time)),
+            Some((time, 12))
+        );
+        assert_eq!(parse_rotation_stamp(&format!("{}-", time)), None);
+        assert_eq!(parse_rotation_stamp("1"), None);
+    }
+
+    #[test]
+    fn rotates_and_reopens_the_daemon_log() {
+        let dir = std::env::temp_dir().join(format!("guarderd-test-{}-log", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("guarderd.log");
+        let log = LogWriter::open(&path, 1, false, Arc::new(Mutex::new(Vec::new()))).unwrap();
+        let mut daemon_log = DaemonLogFile {
+            log,
+            max_size: Arc::new(AtomicU64::new(4 << 10)),
+            reopen_requests: LOG_REOPEN_REQUESTS.load(std::sync::atomic::Ordering::SeqCst),
+        };
+
+        // Rotated once, past 4 KiB
+        let line = format!("{}\n", "x".repeat(1023));
+        for _ in 0..6 {
+            daemon_log.write(&line);
+        }
+        std::fs::rename(&path, dir.join("moved.log")).unwrap();
+        LOG_REOPEN_REQUESTS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        daemon_log.write("after the reopen\n");
+
+        join_compression(daemon_log.log.compression.take());
+        assert!(dir.join("guarderd.log.1.gz").exists());
+        let moved = std::fs::read_to_string(dir.join("moved.log")).unwrap();
+        assert!(moved.contains("Log size exceeded. Rotated") && moved.ends_with(&line));
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, "after the reopen\n");
+    }
+}
@@ -0,0 +1,137 @@
+//! A minimal HTTP server exposing the state of the guard in the Prometheus text format
+
+use anyhow::{Context, Result};
+use std::{
+    fmt::Write as _,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread,
+    time::Duration,
+};
+
+/// How long a client gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The values exposed on each scrape
+#[derive(Debug, Default)]
+pub struct Metrics {
+    pub restarts: u64,
+    pub child_up: bool,
+    pub last_exit_code: Option<i32>,
+    pub child_uptime: Duration,
+    pub log_dropped_bytes: u64,
+    pub log_suppressed_lines: u64,
+}
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, value: &dyn std::fmt::Display| {
+            let _ = write!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}\n"
+            );
+        };
+
+        metric(
+            "guarderd_restarts_total",
+            "counter",
+            "Number of times the child was restarted",
+            &self.restarts,
+        );
+        metric(
+            "guarderd_child_up",
+            "gauge",
+            "Whether the child is running",
+            &u8::from(self.child_up),
+        );
+        // Unknown until the child exits for the first time, or when it was killed by a signal
+        if let Some(code) = self.last_exit_code {
+            metric(
+                "guarderd_last_exit_code",
+                "gauge",
+                "The exit code the child last exited with",
+                &code,
+            );
+        }
+        metric(
+            "guarderd_child_uptime_seconds",
+            "gauge",
+            "How long the current child has been running",
+            &self.child_uptime.as_secs_f64(),
+        );
+        metric(
+            "guarderd_log_dropped_bytes_total",
+            "counter",
+            "Bytes of output dropped with --log-overflow drop",
+            &self.log_dropped_bytes,
+        );
+        metric(
+            "guarderd_log_suppressed_lines_total",
+            "counter",
+            "Lines of output left out of the log by --log-max-lines-per-sec",
+            &self.log_suppressed_lines,
+        );
+        out
+    }
+}
+
+/// Binds the address, before daemonizing so that a port in use is reported on the terminal
+pub fn listen(addr: &str) -> Result<TcpListener> {
+    let listener = TcpListener::bind(addr)
+        .with_context(|| format!("failed to bind metrics address: {}", addr))?;
+    // Accepting is polled, so that the server notices when the guard shuts down
+    listener
+        .set_nonblocking(true)
+        .context("failed to set up metrics listener")?;
+    Ok(listener)
+}
+
+/// Answers `GET /metrics` with the metrics `collect` returns, until `running` flips false
+pub fn serve(listener: TcpListener, running: Arc<AtomicBool>, collect: impl Fn() -> Metrics) {
+    while running.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                // A client that misbehaves only loses its own scrape
+                let _ = respond(stream, &collect);
+            }
+            // Nothing to accept yet, or a transient error such as running out of descriptors
+            Err(_) => thread::sleep(Duration::from_millis(100)),
+        }
+    }
+}
+
+fn respond(stream: TcpStream, collect: &impl Fn() -> Metrics) -> std::io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // The headers are of no interest, but are read so the client doesn't see a reset
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", collect().render()),
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    let mut stream = &stream;
+    write!(
+        stream,
+        "HTTP/1.0 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
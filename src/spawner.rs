@@ -0,0 +1,293 @@
+//! The seam between the supervision loop and the processes it runs: the daemon spawns, waits
+//! for and signals its child and units only through a `ProcessSpawner`, and tells the time of
+//! uptimes and restart delays only through a `Clock`, so that the restart, backoff and give-up
+//! logic can be driven by stand-ins that script how processes exit and how long they ran
+//! instead of forking real ones and waiting for them
+
+use crate::kill_group;
+use nix::{
+    libc,
+    sys::signal::{Signal, killpg},
+    unistd::{self, Pid},
+};
+use std::{
+    collections::BTreeSet,
+    fmt::Debug,
+    io,
+    os::unix::process::ExitStatusExt,
+    process::{Command, ExitStatus},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Starts processes and signals them
+pub trait ProcessSpawner: Debug + Send + Sync {
+    /// Starts the command, which is fully set up, including its stdio and pre_exec hooks
+    fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn ChildProcess>>;
+
+    /// Signals the process group `pid` leads, or led when it was spawned, or the process
+    /// alone if it doesn't lead one
+    fn kill_group(&self, pid: Pid, signal: Signal) -> nix::Result<()>;
+}
+
+/// Tells the time and sleeps
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    fn sleep(&self, duration: Duration);
+}
+
+/// The clock of the system, `Instant::now` and `thread::sleep`
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+/// What a process used over its lifetime, including the children it waited for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    pub user_time: Duration,
+    pub system_time: Duration,
+    /// The peak resident set size
+    pub max_rss_kib: u64,
+}
+
+impl ResourceUsage {
+    fn from_rusage(rusage: &libc::rusage) -> Self {
+        let time = |time: libc::timeval| {
+            Duration::from_secs(time.tv_sec as u64) + Duration::from_micros(time.tv_usec as u64)
+        };
+        ResourceUsage {
+            user_time: time(rusage.ru_utime),
+            system_time: time(rusage.ru_stime),
+            // Linux reports it in KiB
+            max_rss_kib: rusage.ru_maxrss as u64,
+        }
+    }
+}
+
+impl std::fmt::Display for ResourceUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.2}s user and {:.2}s system CPU time, {:.1} MiB max RSS",
+            self.user_time.as_secs_f64(),
+            self.system_time.as_secs_f64(),
+            self.max_rss_kib as f64 / 1024.0
+        )
+    }
+}
+
+/// A process started by a `ProcessSpawner`
+pub trait ChildProcess: Send {
+    fn id(&self) -> u32;
+
+    /// Waits for the process to exit, returning what it used
+    fn wait(&mut self) -> io::Result<(ExitStatus, ResourceUsage)>;
+
+    /// The exit status if the process has exited, without waiting
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>>;
+
+    /// Kills the process itself with SIGKILL
+    fn kill(&mut self) -> io::Result<()>;
+}
+
+/// Spawns real processes with `std::process::Command`
+#[derive(Debug, Default)]
+pub struct OsSpawner {
+    // The processes spawned leading a process group, recorded while they still do. Their
+    // group is signaled even after they exited, reaching what they forked and left behind
+    group_leaders: Mutex<BTreeSet<Pid>>,
+}
+
+impl ProcessSpawner for OsSpawner {
+    fn spawn(&self, command: &mut Command) -> io::Result<Box<dyn ChildProcess>> {
+        let child = command.spawn()?;
+        let pid = Pid::from_raw(child.id() as i32);
+        let mut group_leaders = self.group_leaders.lock().unwrap();
+        // Forgotten once nothing is left in their group, its ID may be reused afterwards
+        group_leaders.retain(|&pgid| killpg(pgid, None).is_ok());
+        // Not reaped yet, so even a process that exited right away still tells its group
+        if unistd::getpgid(Some(pid)) == Ok(pid) {
+            group_leaders.insert(pid);
+        }
+        Ok(Box::new(child))
+    }
+
+    fn kill_group(&self, pid: Pid, signal: Signal) -> nix::Result<()> {
+        let mut group_leaders = self.group_leaders.lock().unwrap();
+        if group_leaders.contains(&pid) {
+            if killpg(pid, None).is_ok() {
+                return killpg(pid, signal);
+            }
+            // The group is gone, its ID may belong to another one by now
+            group_leaders.remove(&pid);
+        }
+        drop(group_leaders);
+        kill_group(pid, signal)
+    }
+}
+
+impl ChildProcess for std::process::Child {
+    fn id(&self) -> u32 {
+        std::process::Child::id(self)
+    }
+
+    /// Reaps the process with wait4, as `Child::wait` doesn't return the resource usage
+    fn wait(&mut self) -> io::Result<(ExitStatus, ResourceUsage)> {
+        let pid = std::process::Child::id(self) as libc::pid_t;
+        let mut status = 0;
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        while unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) } < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(err);
+            }
+        }
+        Ok((
+            ExitStatus::from_raw(status),
+            ResourceUsage::from_rusage(&rusage),
+        ))
+    }
+
+    fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        std::process::Child::try_wait(self)
+    }
+
+    fn kill(&mut self) -> io::Result<()> {
+        std::process::Child::kill(self)
+    }
+}
+
+/// Stands in for `OsSpawner` in tests
+#[cfg(test)]
+pub mod scripted {
+    use super::{ChildProcess, Clock, ProcessSpawner, ResourceUsage};
+    use nix::{sys::signal::Signal, unistd::Pid};
+    use std::{
+        collections::VecDeque,
+        io,
+        os::unix::process::ExitStatusExt,
+        process::{Command, ExitStatus},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    };
+
+    /// A clock that only moves when it is slept on, right away
+    #[derive(Debug)]
+    pub struct ScriptedClock {
+        now: Mutex<Instant>,
+    }
+
+    impl Default for ScriptedClock {
+        fn default() -> Self {
+            ScriptedClock {
+                now: Mutex::new(Instant::now()),
+            }
+        }
+    }
+
+    impl Clock for ScriptedClock {
+        fn now(&self) -> Instant {
+            *self.now.lock().unwrap()
+        }
+
+        fn sleep(&self, duration: Duration) {
+            *self.now.lock().unwrap() += duration;
+        }
+    }
+
+    /// Each spawn returns a process that exits with the next of the scripted exit codes
+    /// once it ran for its uptime on the scripted clock, and is recorded along with when
+    /// it happened
+    #[derive(Debug, Default)]
+    pub struct ScriptedSpawner {
+        runs: Mutex<VecDeque<(i32, Duration)>>,
+        spawns: Mutex<Vec<Instant>>,
+        clock: Arc<ScriptedClock>,
+    }
+
+    impl ScriptedSpawner {
+        /// Processes that exit as soon as they are spawned
+        pub fn new(exit_codes: impl IntoIterator<Item = i32>) -> Self {
+            Self::with_uptimes(exit_codes.into_iter().map(|code| (code, Duration::ZERO)))
+        }
+
+        /// Processes that exit with the code after running for the duration
+        pub fn with_uptimes(runs: impl IntoIterator<Item = (i32, Duration)>) -> Self {
+            ScriptedSpawner {
+                runs: Mutex::new(runs.into_iter().collect()),
+                ..Default::default()
+            }
+        }
+
+        /// The clock the processes run on, for the daemon to tell the time with
+        pub fn clock(&self) -> Arc<ScriptedClock> {
+            self.clock.clone()
+        }
+
+        /// When each process was spawned on the scripted clock, in order
+        pub fn spawns(&self) -> Vec<Instant> {
+            self.spawns.lock().unwrap().clone()
+        }
+    }
+
+    impl ProcessSpawner for ScriptedSpawner {
+        /// Fails once the script has run out, like a command that can't be spawned
+        fn spawn(&self, _command: &mut Command) -> io::Result<Box<dyn ChildProcess>> {
+            let (code, uptime) = self
+                .runs
+                .lock()
+                .unwrap()
+                .pop_front()
+                .ok_or_else(|| io::Error::other("no scripted exit left"))?;
+            let mut spawns = self.spawns.lock().unwrap();
+            spawns.push(self.clock.now());
+            Ok(Box::new(ScriptedProcess {
+                // Above the default pid_max, so that nothing real is signaled by mistake
+                pid: 5_000_000 + spawns.len() as u32,
+                status: ExitStatus::from_raw(code << 8),
+                uptime,
+                clock: self.clock.clone(),
+            }))
+        }
+
+        fn kill_group(&self, _pid: Pid, _signal: Signal) -> nix::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct ScriptedProcess {
+        pid: u32,
+        status: ExitStatus,
+        uptime: Duration,
+        clock: Arc<ScriptedClock>,
+    }
+
+    impl ChildProcess for ScriptedProcess {
+        fn id(&self) -> u32 {
+            self.pid
+        }
+
+        fn wait(&mut self) -> io::Result<(ExitStatus, ResourceUsage)> {
+            self.clock.sleep(std::mem::take(&mut self.uptime));
+            Ok((self.status, ResourceUsage::default()))
+        }
+
+        fn try_wait(&mut self) -> io::Result<Option<ExitStatus>> {
+            Ok(Some(self.status))
+        }
+
+        fn kill(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+}
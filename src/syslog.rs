@@ -0,0 +1,95 @@
+//! Forwards the output of the child to the local syslog daemon, one record per line
+
+use clap::ValueEnum;
+use serde::Deserialize;
+use std::{io, os::unix::net::UnixDatagram, path::Path};
+
+/// The socket local syslog daemons listen on
+const SYSLOG_SOCKET: &str = "/dev/log";
+
+/// The facilities programs outside of the system itself may log as
+#[derive(ValueEnum, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Facility {
+    User,
+    Daemon,
+    Local0,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+impl Facility {
+    fn code(self) -> u8 {
+        match self {
+            Facility::User => 1,
+            Facility::Daemon => 3,
+            Facility::Local0 => 16,
+            Facility::Local1 => 17,
+            Facility::Local2 => 18,
+            Facility::Local3 => 19,
+            Facility::Local4 => 20,
+            Facility::Local5 => 21,
+            Facility::Local6 => 22,
+            Facility::Local7 => 23,
+        }
+    }
+}
+
+/// How severe the records are, stdout is logged as info and a separate stderr as errors
+#[derive(Clone, Copy, Debug)]
+pub enum Severity {
+    Error = 3,
+    Info = 6,
+}
+
+/// A connection to the syslog socket sending every record with the same priority and tag
+#[derive(Debug)]
+pub struct Syslog {
+    socket: UnixDatagram,
+    priority: u8,
+    tag: String,
+    pid: u32,
+}
+
+impl Syslog {
+    pub fn connect(facility: Facility, severity: Severity, tag: &str) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(SYSLOG_SOCKET)?;
+        Ok(Syslog {
+            socket,
+            priority: facility.code() * 8 + severity as u8,
+            tag: tag.to_string(),
+            pid: std::process::id(),
+        })
+    }
+
+    /// Sends a line as one record, without its line ending. The syslog daemon adds the
+    /// timestamp and hostname
+    pub fn send(&mut self, line: &[u8]) -> io::Result<()> {
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut record = format!("<{}>{}[{}]: ", self.priority, self.tag, self.pid).into_bytes();
+        record.extend_from_slice(line);
+
+        if self.socket.send(&record).is_err() {
+            // The syslog daemon may have been restarted, which replaces its socket
+            self.socket = UnixDatagram::unbound()?;
+            self.socket.connect(SYSLOG_SOCKET)?;
+            self.socket.send(&record)?;
+        }
+        Ok(())
+    }
+}
+
+/// The default tag, the name of the program the child runs
+pub fn default_tag(program: &str) -> String {
+    Path::new(program).file_name().map_or_else(
+        || program.to_string(),
+        |name| name.to_string_lossy().into_owned(),
+    )
+}
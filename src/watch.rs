@@ -0,0 +1,47 @@
+//! Detects changes to watched files by polling their modification times and sizes, so
+//! that the child can be restarted when its sources change
+
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+/// The modification time and size of every file and directory under the watched paths
+#[derive(Debug, PartialEq, Eq)]
+pub struct Snapshot(BTreeMap<PathBuf, (Option<SystemTime>, u64)>);
+
+impl Snapshot {
+    /// Scans the paths, descending into directories. Symlinks given as a watched path are
+    /// followed, those found inside a watched directory are not, so cycles can't occur
+    pub fn scan(paths: &[PathBuf]) -> Self {
+        let mut files = BTreeMap::new();
+        for path in paths {
+            if let Ok(metadata) = std::fs::metadata(path) {
+                scan_entry(path, &metadata, &mut files);
+            }
+        }
+        Snapshot(files)
+    }
+}
+
+fn scan_entry(
+    path: &Path,
+    metadata: &std::fs::Metadata,
+    files: &mut BTreeMap<PathBuf, (Option<SystemTime>, u64)>,
+) {
+    files.insert(path.to_path_buf(), (metadata.modified().ok(), metadata.len()));
+    if !metadata.is_dir() {
+        return;
+    }
+
+    // Entries vanishing in the middle of a scan are simply missing from this snapshot
+    let Ok(entries) = std::fs::read_dir(path) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if let Ok(metadata) = std::fs::symlink_metadata(entry.path()) {
+            scan_entry(&entry.path(), &metadata, files);
+        }
+    }
+}